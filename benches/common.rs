@@ -1,4 +1,13 @@
-use bevy::{color::palettes::tailwind, prelude::*, render::mesh::MeshPlugin};
+use bevy::{
+    asset::RenderAssetUsages,
+    color::palettes::tailwind,
+    math::bounding::RayCast3d,
+    prelude::*,
+    render::mesh::{
+        Indices, MeshPlugin, MeshVertexAttribute, PrimitiveTopology, VertexAttributeValues,
+        VertexFormat,
+    },
+};
 use raven_bvh::prelude::*;
 
 #[test]
@@ -59,6 +68,2002 @@ fn camera() {
     //assert_eq!(app.world().get::<Enemy>(enemy_id).unwrap().hit_points, 4);
 }
 
+#[test]
+fn watertight() {
+    let settings = RayCastSettings {
+        watertight: true,
+        ..default()
+    };
+    // directions spread over the sphere, plus the axes and diagonals that pass exactly
+    // through the shared edges and corners of the cuboids
+    let mut directions = golden_spiral(20_000);
+    for x in [-1.0, 0.0, 1.0] {
+        for y in [-1.0, 0.0, 1.0] {
+            for z in [-1.0, 0.0, 1.0] {
+                directions.push(Vec3A::new(x, y, z));
+            }
+        }
+    }
+
+    for (name, mesh) in [
+        ("cuboid", Cuboid::new(1.0, 1.0, 1.0).mesh().build()),
+        ("uneven cuboid", Cuboid::new(1.3, 0.7, 2.1).mesh().build()),
+        ("ico sphere", Sphere::new(1.0).mesh().ico(4).unwrap()),
+        ("uv sphere", Sphere::new(1.0).mesh().uv(32, 18)),
+    ] {
+        for wide in [false, true] {
+            let bvh =
+                Bvh::try_from_mesh_with_settings(&mesh, BvhBuildSettings { wide, ..default() })
+                    .unwrap();
+            // every ray from inside a closed mesh has to hit it
+            for origin in [Vec3A::ZERO, Vec3A::new(0.01, -0.02, 0.03)] {
+                let misses = directions
+                    .iter()
+                    .filter_map(|direction| Dir3A::new(*direction).ok())
+                    .filter(|direction| {
+                        RayCast3d::new(origin, *direction, f32::MAX)
+                            .intersect_bvh_with_settings(&bvh, &settings)
+                            .is_none()
+                    })
+                    .count();
+                assert_eq!(misses, 0, "{name} (wide: {wide}) from {origin}");
+            }
+        }
+    }
+}
+
+#[test]
+fn build_settings_clamped() {
+    let mesh = Sphere::new(1.0).mesh().ico(3).unwrap();
+    let tri = Tri::new(Vec3A::ZERO, Vec3A::X, Vec3A::Y);
+    for (spatial_splits, linear) in [(false, false), (true, false), (false, true)] {
+        for (bin_count, min_leaf_size) in [(0, 0), (1, 4)] {
+            let settings = BvhBuildSettings {
+                bin_count,
+                min_leaf_size,
+                max_leaf_size: 0,
+                spatial_splits,
+                linear,
+                ..default()
+            };
+            for bvh in [
+                Bvh::try_from_mesh_with_settings(&mesh, settings.clone()).unwrap(),
+                Bvh::new_with_settings(vec![tri], settings.clone()),
+            ] {
+                assert_eq!(bvh.settings.bin_count, 2);
+                assert_eq!(bvh.settings.max_leaf_size, 1);
+                assert!(bvh.settings.min_leaf_size <= 1);
+
+                // every triangle is reachable, and only through leaves that hold some
+                let mut found = vec![false; bvh.tris.len()];
+                let mut stack = vec![0];
+                let mut visited = 0;
+                while let Some(node_idx) = stack.pop() {
+                    visited += 1;
+                    assert!(visited <= bvh.nodes.len(), "{settings:?}");
+                    let node = &bvh.nodes[node_idx];
+                    if node.is_leaf() {
+                        assert!(node.tri_count <= 1, "{settings:?}");
+                        for i in 0..node.tri_count {
+                            found[bvh.triangle_indexs[(node.left_first + i) as usize]] = true;
+                        }
+                    } else {
+                        assert!((node.left_first as usize) < bvh.nodes.len(), "{settings:?}");
+                        stack.push(node.left_first as usize);
+                        stack.push(node.left_first as usize + 1);
+                    }
+                }
+                assert!(found.iter().all(|found| *found), "{settings:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn parallel_build() {
+    use bevy::tasks::{ComputeTaskPool, TaskPool};
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+
+    // without a pool the parallel build quietly runs serially
+    ComputeTaskPool::get_or_init(TaskPool::default);
+    let mut rng = ChaChaRng::seed_from_u64(7);
+    let mut random_vec3 = || {
+        vec3a(
+            rng.random_range(-1.0..=1.0),
+            rng.random_range(-1.0..=1.0),
+            rng.random_range(-1.0..=1.0),
+        )
+    };
+    // well above the threshold, so several levels are built in parallel
+    let tris = (0..50_000)
+        .map(|_| {
+            let v0 = random_vec3() * 20.0;
+            Tri::new(v0, v0 + random_vec3(), v0 + random_vec3())
+        })
+        .collect::<Vec<_>>();
+
+    for max_leaf_size in [u32::MAX, 4] {
+        let build = |parallel| {
+            Bvh::new_with_settings(
+                tris.clone(),
+                BvhBuildSettings {
+                    parallel,
+                    max_leaf_size,
+                    ..default()
+                },
+            )
+        };
+        let serial = build(false);
+        let parallel = build(true);
+        assert!(serial.nodes.len() > 1);
+        assert_eq!(serial.nodes.len(), parallel.nodes.len());
+        assert!(serial.nodes.iter().eq(parallel.nodes.iter()));
+        assert_eq!(serial.triangle_indexs, parallel.triangle_indexs);
+    }
+}
+
+#[test]
+fn bvh_format_round_trip() {
+    let sphere = Sphere::new(1.0).mesh().ico(2).unwrap();
+    let cube = Cuboid::new(1.0, 2.0, 3.0).mesh().build();
+    let bvh = Bvh::try_from_sub_meshes(
+        &[
+            BvhSubMesh {
+                mesh: &sphere,
+                user_id: 3,
+            },
+            BvhSubMesh {
+                mesh: &cube,
+                user_id: 7,
+            },
+        ],
+        BvhBuildSettings {
+            spatial_splits: true,
+            wide: true,
+            ..default()
+        },
+    )
+    .unwrap();
+
+    let loaded = Bvh::from_bytes(&bvh.to_bytes()).unwrap();
+    assert_eq!(loaded.settings, bvh.settings);
+    assert_eq!(loaded.build_cost, bvh.build_cost);
+    assert!(loaded.nodes.iter().eq(bvh.nodes.iter()));
+    assert_eq!(loaded.triangle_indexs, bvh.triangle_indexs);
+    assert_eq!(loaded.tri_sources, bvh.tri_sources);
+    assert_eq!(loaded.tris.len(), bvh.tris.len());
+    for (a, b) in loaded.tris.iter().zip(&bvh.tris) {
+        assert_eq!(
+            [a.vertex0, a.vertex1, a.vertex2],
+            [b.vertex0, b.vertex1, b.vertex2]
+        );
+    }
+    assert!(loaded.wide.is_some());
+}
+
+#[test]
+fn bvh_format_errors() {
+    let bvh = Bvh::try_from_mesh(&Sphere::new(1.0).mesh().ico(1).unwrap()).unwrap();
+    let bytes = bvh.to_bytes();
+    // header size and field offsets, see `Bvh::to_bytes`
+    let nodes_start = 68;
+    let tris_start = nodes_start + bvh.nodes.len() * 32;
+    let indexs_start = tris_start + bvh.tris.len() * 36;
+    let corrupt = |offset: usize, value: u32| {
+        let mut bytes = bytes.clone();
+        bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        Bvh::from_bytes(&bytes)
+    };
+
+    for len in 0..bytes.len() {
+        let result = Bvh::from_bytes(&bytes[..len]);
+        if len < 8 {
+            assert!(matches!(result, Err(BvhFormatError::InvalidMagic)), "{len}");
+        } else {
+            assert!(
+                matches!(result, Err(BvhFormatError::SizeMismatch { found, .. }) if found == len),
+                "{len}"
+            );
+        }
+    }
+    // counts far past the end of the file, which would overflow the size on 32 bit targets
+    for count_offset in [52, 56, 60, 64] {
+        assert!(matches!(
+            corrupt(count_offset, u32::MAX),
+            Err(BvhFormatError::SizeMismatch { found, .. }) if found == bytes.len()
+        ));
+    }
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert!(matches!(
+        Bvh::from_bytes(&longer),
+        Err(BvhFormatError::SizeMismatch { .. })
+    ));
+
+    assert!(matches!(
+        corrupt(0, u32::from_le_bytes(*b"NOPE")),
+        Err(BvhFormatError::InvalidMagic)
+    ));
+    assert!(matches!(
+        corrupt(4, BVH_FORMAT_VERSION + 1),
+        Err(BvhFormatError::UnsupportedVersion(version)) if version == BVH_FORMAT_VERSION + 1
+    ));
+
+    // the root's children past the end of the nodes
+    assert!(!bvh.nodes[0].is_leaf());
+    assert!(matches!(
+        corrupt(nodes_start + 12, bvh.nodes.len() as u32),
+        Err(BvhFormatError::InvalidNode(0))
+    ));
+    // a leaf's triangles past the end of the triangle indexes
+    let leaf = bvh.nodes.iter().position(|node| node.is_leaf()).unwrap();
+    assert!(matches!(
+        corrupt(leaf * 32 + nodes_start + 28, bvh.triangle_indexs.len() as u32 + 1),
+        Err(BvhFormatError::InvalidNode(i)) if i == leaf
+    ));
+    // a triangle index past the end of the triangles
+    assert!(matches!(
+        corrupt(indexs_start + 8, bvh.tris.len() as u32),
+        Err(BvhFormatError::InvalidTriangleIndex(2))
+    ));
+}
+
+#[test]
+fn intersect_bvh_all() {
+    let tris = slanted_tris();
+    let sbvh = Bvh::new_with_settings(
+        tris.clone(),
+        BvhBuildSettings {
+            spatial_splits: true,
+            max_leaf_size: 2,
+            ..default()
+        },
+    );
+    assert!(sbvh.triangle_indexs.len() > sbvh.tris.len());
+
+    // slanted rays through a grid, many cross several leaves sharing a triangle
+    let origins =
+        (-5..=5).flat_map(|y| (-5..=5).map(move |z| vec3a(-20.0, y as f32 * 4.0, z as f32 * 8.0)));
+    let directions = [vec3a(1.0, 0.5, 0.25), vec3a(1.0, -0.4, 0.3)];
+    for (origin, direction) in origins.flat_map(|origin| directions.map(|dir| (origin, dir))) {
+        let ray = RayCast3d::new(origin, Dir3A::new(direction).unwrap(), f32::MAX);
+        let expected = tris
+            .iter()
+            .enumerate()
+            .filter_map(|(i, tri)| ray.intersect_triangle(tri, i))
+            .count();
+
+        let hits = ray.intersect_bvh_all(&sbvh, None);
+        assert_eq!(hits.len(), expected);
+        // each triangle once, closest first
+        let mut tri_indexs = hits.iter().map(|hit| hit.tri_index).collect::<Vec<_>>();
+        tri_indexs.sort_unstable();
+        tri_indexs.dedup();
+        assert_eq!(tri_indexs.len(), expected);
+        assert!(hits.windows(2).all(|w| w[0].distance <= w[1].distance));
+
+        // the closest few, same as the start of every hit
+        let closest = ray.intersect_bvh_all(&sbvh, Some(5));
+        assert_eq!(closest.len(), expected.min(5));
+        for (a, b) in closest.iter().zip(&hits) {
+            assert_eq!(a.distance, b.distance);
+        }
+        assert_eq!(ray.intersect_bvh_all(&sbvh, Some(0)).len(), 0);
+        assert_eq!(ray.intersect_bvh_all(&sbvh, Some(1000)).len(), expected);
+    }
+}
+
+#[test]
+fn packet_matches_scalar() {
+    // rays from around the sphere, aimed at points near it so some miss
+    let count = 61;
+    let points = golden_spiral(count);
+    let rays = (0..count)
+        .map(|i| {
+            let origin = points[i] * 3.0;
+            let target = points[(i * 7) % count] * 1.2;
+            RayCast3d::new(origin, Dir3A::new(target - origin).unwrap(), f32::MAX)
+        })
+        .collect::<Vec<_>>();
+
+    let mesh = Sphere::new(1.0).mesh().ico(3).unwrap();
+    for wide in [false, true] {
+        let bvh = Bvh::try_from_mesh_with_settings(&mesh, BvhBuildSettings { wide, ..default() })
+            .unwrap();
+        for settings in [
+            RayCastSettings::default(),
+            RayCastSettings {
+                watertight: true,
+                ..default()
+            },
+            RayCastSettings {
+                cull_mode: CullMode::Back,
+                ..default()
+            },
+            RayCastSettings {
+                cull_mode: CullMode::Front,
+                watertight: true,
+                ..default()
+            },
+        ] {
+            // full and partial packets
+            for packet_size in 1..=RAY_PACKET_WIDTH {
+                for rays in rays.chunks(packet_size) {
+                    let hits = RayPacket::new(rays).intersect_bvh_with_settings(&bvh, &settings);
+                    for (lane, hit) in hits.iter().enumerate() {
+                        let expected = rays
+                            .get(lane)
+                            .and_then(|ray| ray.intersect_bvh_with_settings(&bvh, &settings));
+                        let context = format!("wide: {wide}, {settings:?}, lane {lane}");
+                        assert_eq!(hit.is_some(), expected.is_some(), "{context}");
+                        if let (Some(hit), Some(expected)) = (hit, expected) {
+                            assert!((hit.distance - expected.distance).abs() < 1e-4, "{context}");
+                            // rays through a shared edge may report either triangle
+                            let tri_hit = rays[lane].intersect_triangle_with_settings(
+                                &bvh.tris[hit.tri_index],
+                                hit.tri_index,
+                                &settings,
+                            );
+                            assert!(
+                                tri_hit.is_some_and(|tri_hit| {
+                                    (tri_hit.distance - hit.distance).abs() < 1e-4
+                                }),
+                                "{context}"
+                            );
+                            assert_eq!(hit.front_face, expected.front_face, "{context}");
+                            assert!(hit.point.distance(expected.point) < 1e-4, "{context}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn closest_point_regions() {
+    let tri = Tri::new(Vec3A::ZERO, Vec3A::X, Vec3A::Y);
+    let bvh = Bvh::new(vec![tri]);
+    // query point, closest point, barycentrics
+    for (point, closest, barycentrics) in [
+        // vertex regions
+        (vec3a(-1.0, -1.0, 0.5), Vec3A::ZERO, vec3(1.0, 0.0, 0.0)),
+        (vec3a(2.0, -0.5, 0.0), Vec3A::X, vec3(0.0, 1.0, 0.0)),
+        (vec3a(-0.5, 2.0, 1.0), Vec3A::Y, vec3(0.0, 0.0, 1.0)),
+        // edge regions
+        (
+            vec3a(0.5, -1.0, 0.3),
+            vec3a(0.5, 0.0, 0.0),
+            vec3(0.5, 0.5, 0.0),
+        ),
+        (
+            vec3a(-1.0, 0.25, 0.0),
+            vec3a(0.0, 0.25, 0.0),
+            vec3(0.75, 0.0, 0.25),
+        ),
+        (
+            vec3a(1.0, 1.0, 2.0),
+            vec3a(0.5, 0.5, 0.0),
+            vec3(0.0, 0.5, 0.5),
+        ),
+        // face region, from either side
+        (
+            vec3a(0.2, 0.3, 5.0),
+            vec3a(0.2, 0.3, 0.0),
+            vec3(0.5, 0.2, 0.3),
+        ),
+        (
+            vec3a(0.2, 0.3, -5.0),
+            vec3a(0.2, 0.3, 0.0),
+            vec3(0.5, 0.2, 0.3),
+        ),
+    ] {
+        let result = bvh.closest_point(point, f32::MAX).unwrap();
+        assert!(result.point.distance(closest) < 1e-5, "{point}: {result:?}");
+        assert!(
+            (result.distance - point.distance(closest)).abs() < 1e-5,
+            "{point}: {result:?}"
+        );
+        assert!(
+            result.barycentrics.distance(barycentrics) < 1e-5,
+            "{point}: {result:?}"
+        );
+        assert_eq!(result.tri_index, 0);
+    }
+    // nothing within max_distance
+    assert!(bvh.closest_point(vec3a(0.2, 0.3, 5.0), 4.9).is_none());
+
+    // same vertex order as a ray hit at the same point
+    let ray = RayCast3d::new(vec3a(0.2, 0.3, 1.0), Dir3A::NEG_Z, f32::MAX);
+    let hit = ray.intersect_bvh(&bvh).unwrap();
+    let result = bvh.closest_point(vec3a(0.2, 0.3, 1.0), f32::MAX).unwrap();
+    assert!(hit.barycentrics().distance(result.barycentrics) < 1e-5);
+}
+
+#[test]
+fn closest_point_scaled_instance() {
+    use bevy::ecs::system::SystemState;
+
+    let mut app = test_app();
+    let bvh = Bvh::try_from_mesh(&Sphere::new(1.0).mesh().ico(2).unwrap()).unwrap();
+    // squashed and stretched, the closest point isn't the scaled local closest point
+    let transform = Transform::from_xyz(1.0, -2.0, 0.5)
+        .with_rotation(Quat::from_euler(EulerRot::XYZ, 0.3, 0.7, -0.2))
+        .with_scale(vec3(4.0, 1.0, 0.5));
+    let world = world_bvh(&bvh, transform);
+    let handle = app.world_mut().resource_mut::<Assets<Bvh>>().add(bvh);
+    let entity = app.world_mut().spawn((MeshBvh(handle), transform)).id();
+    app.update();
+    app.update();
+
+    let mut state: SystemState<TlasCast> = SystemState::new(app.world_mut());
+    let cast = state.get(app.world());
+    for x in -3..=3 {
+        for y in -3..=3 {
+            for z in -3..=3 {
+                let point = vec3a(x as f32 * 2.0, y as f32, z as f32);
+                let expected = world.closest_point(point, f32::MAX).unwrap();
+                let (e, result) = cast.closest_point(point, f32::MAX).unwrap();
+                assert_eq!(e, entity);
+                assert!(
+                    (result.distance - expected.distance).abs() < 1e-4,
+                    "{point}: {result:?} {expected:?}"
+                );
+                assert!(
+                    result.point.distance(expected.point) < 1e-3,
+                    "{point}: {result:?} {expected:?}"
+                );
+                assert!((result.point.distance(point) - result.distance).abs() < 1e-4);
+            }
+        }
+    }
+}
+
+#[test]
+fn overlap_instances() {
+    use bevy::{
+        ecs::system::SystemState,
+        math::bounding::{Aabb3d, BoundingSphere},
+    };
+
+    let mut app = test_app();
+    // a long thin triangle along the diagonal, its world box covers the whole square
+    let diagonal = Bvh::new(vec![Tri::new(
+        vec3a(-5.0, -0.1, 0.0),
+        vec3a(5.0, -0.1, 0.0),
+        vec3a(0.0, 0.1, 0.0),
+    )]);
+    let diagonal = app.world_mut().resource_mut::<Assets<Bvh>>().add(diagonal);
+    let rotated = app
+        .world_mut()
+        .spawn((
+            MeshBvh(diagonal),
+            Transform::from_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_4)),
+        ))
+        .id();
+    // a box stretched along x and squashed along z
+    let cuboid = Bvh::try_from_mesh(&Cuboid::new(2.0, 2.0, 2.0).mesh().build()).unwrap();
+    let scale = Transform::from_xyz(0.0, 10.0, 0.0).with_scale(vec3(4.0, 1.0, 0.5));
+    let world_cuboid = world_bvh(&cuboid, scale);
+    let cuboid = app.world_mut().resource_mut::<Assets<Bvh>>().add(cuboid);
+    let scaled = app.world_mut().spawn((MeshBvh(cuboid), scale)).id();
+    app.update();
+    app.update();
+
+    let mut state: SystemState<TlasCast> = SystemState::new(app.world_mut());
+    let cast = state.get(app.world());
+
+    // inside the rotated instance's world box, but away from its triangle
+    let corner = Aabb3d::new(vec3(3.0, -3.0, 0.0), Vec3::splat(0.2));
+    assert!(cast.overlap_aabb(&corner).is_empty());
+    assert!(cast.overlap_aabb_triangles(&corner).is_empty());
+    let corner = BoundingSphere::new(vec3(3.0, -3.0, 0.0), 0.5);
+    assert!(cast.overlap_sphere(&corner).is_empty());
+    let on_diagonal = Aabb3d::new(vec3(2.0, 2.0, 0.0), Vec3::splat(0.2));
+    assert_eq!(cast.overlap_aabb(&on_diagonal), vec![rotated]);
+    assert_eq!(
+        cast.overlap_aabb_triangles(&on_diagonal),
+        vec![(rotated, vec![0])]
+    );
+
+    // spheres near the faces of the scaled box, x faces at 4, y at 11 and z at 0.5
+    for (center, radius, touches) in [
+        (vec3(4.3, 10.0, 0.0), 0.5, true),
+        (vec3(4.6, 10.0, 0.0), 0.5, false),
+        (vec3(0.0, 11.2, 0.0), 0.3, true),
+        (vec3(0.0, 11.4, 0.0), 0.3, false),
+        (vec3(0.0, 10.0, 0.75), 0.3, true),
+        (vec3(0.0, 10.0, 0.9), 0.3, false),
+        (vec3(1.0, 10.5, 0.2), 0.5, true),
+        // inside, but touching no triangle
+        (vec3(0.0, 10.0, 0.0), 0.2, false),
+    ] {
+        let sphere = BoundingSphere::new(center, radius);
+        let expected = touches.then_some(scaled).into_iter().collect::<Vec<_>>();
+        assert_eq!(cast.overlap_sphere(&sphere), expected, "{center} {radius}");
+        let mut expected = world_cuboid.overlap_sphere(&sphere);
+        expected.sort_unstable();
+        let found = cast.overlap_sphere_triangles(&sphere);
+        if touches {
+            assert_eq!(found.len(), 1);
+            let mut tris = found[0].1.clone();
+            tris.sort_unstable();
+            assert_eq!(tris, expected, "{center} {radius}");
+        } else {
+            assert!(found.is_empty() && expected.is_empty(), "{center} {radius}");
+        }
+    }
+}
+
+#[test]
+fn overlap_sbvh() {
+    use bevy::math::bounding::{Aabb3d, BoundingSphere};
+
+    let tris = slanted_tris();
+    let bvh = Bvh::new(tris.clone());
+    let sbvh = Bvh::new_with_settings(
+        tris,
+        BvhBuildSettings {
+            spatial_splits: true,
+            max_leaf_size: 2,
+            ..default()
+        },
+    );
+    assert!(sbvh.triangle_indexs.len() > sbvh.tris.len());
+
+    for center in [
+        vec3(10.0, 0.0, 0.0),
+        vec3(30.0, 20.0, 10.0),
+        vec3(5.0, -30.0, -40.0),
+    ] {
+        let aabb = Aabb3d::new(center, Vec3::splat(15.0));
+        let sphere = BoundingSphere::new(center, 15.0);
+        for (expected, found) in [
+            (bvh.overlap_aabb(&aabb), sbvh.overlap_aabb(&aabb)),
+            (bvh.overlap_sphere(&sphere), sbvh.overlap_sphere(&sphere)),
+        ] {
+            let mut expected = expected;
+            expected.sort_unstable();
+            assert!(!expected.is_empty());
+            // each triangle once
+            let mut unique = found.clone();
+            unique.sort_unstable();
+            unique.dedup();
+            assert_eq!(unique.len(), found.len(), "{center}");
+            assert_eq!(unique, expected, "{center}");
+        }
+    }
+}
+
+#[test]
+fn mesh_bvh_error_retried() {
+    let mut app = test_app();
+    // the last index is past the end of the vertices
+    let broken = || {
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, vec![Vec3::ZERO, Vec3::X, Vec3::Y])
+        .with_inserted_indices(Indices::U32(vec![0, 1, 3]))
+    };
+    let mut meshes = app.world_mut().resource_mut::<Assets<Mesh>>();
+    let fixed = meshes.add(broken());
+    let swapped = meshes.add(broken());
+    let cuboid = meshes.add(Cuboid::default());
+    let settings = BvhBuildSettings {
+        max_leaf_size: 4,
+        ..default()
+    };
+    let fixed_e = app
+        .world_mut()
+        .spawn((Mesh3d(fixed.clone()), settings, SpawnMeshBvh))
+        .id();
+    let swapped_e = app.world_mut().spawn((Mesh3d(swapped), SpawnMeshBvh)).id();
+    app.update();
+    for e in [fixed_e, swapped_e] {
+        let entity = app.world().entity(e);
+        assert!(!entity.contains::<MeshBvh>());
+        assert!(matches!(
+            entity.get::<MeshBvhError>(),
+            Some(MeshBvhError(BvhBuildError::IndexOutOfBounds {
+                index: 3,
+                vertex_count: 3
+            }))
+        ));
+    }
+
+    // fixing the mesh or swapping in another both build the bvh
+    app.world_mut()
+        .resource_mut::<Assets<Mesh>>()
+        .get_mut(&fixed)
+        .unwrap()
+        .insert_indices(Indices::U32(vec![0, 1, 2]));
+    app.world_mut().entity_mut(swapped_e).insert(Mesh3d(cuboid));
+    app.update();
+    app.update();
+    for (e, tri_count) in [(fixed_e, 1), (swapped_e, 12)] {
+        let entity = app.world().entity(e);
+        assert!(!entity.contains::<MeshBvhError>());
+        let handle = entity
+            .get::<MeshBvh>()
+            .expect("Bvh not built after fixing the mesh");
+        let bvhs = app.world().resource::<Assets<Bvh>>();
+        let bvh = bvhs.get(&handle.0).unwrap();
+        assert_eq!(bvh.tris.len(), tri_count);
+    }
+    // built with the settings the failed build was given
+    let handle = app.world().get::<MeshBvh>(fixed_e).unwrap();
+    let bvhs = app.world().resource::<Assets<Bvh>>();
+    assert_eq!(bvhs.get(&handle.0).unwrap().settings.max_leaf_size, 4);
+}
+
+#[test]
+fn async_mesh_bvh_rebuilds() {
+    use bevy::ecs::system::SystemState;
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+
+    let mut app = test_app();
+    app.insert_resource(BvhBuildMode::Async);
+    // big enough that builds take a few frames
+    let source = Sphere::new(1.0).mesh().ico(40).unwrap();
+    let h_mesh = app
+        .world_mut()
+        .resource_mut::<Assets<Mesh>>()
+        .add(source.clone());
+    let settings = BvhBuildSettings {
+        max_leaf_size: 4,
+        ..default()
+    };
+    let e = app
+        .world_mut()
+        .spawn((
+            Mesh3d(h_mesh.clone()),
+            Transform::default(),
+            settings,
+            SpawnMeshBvh,
+        ))
+        .id();
+
+    // updates until the background builds are done, the entity has to stay in the tlas while
+    // any rebuild is pending
+    let mut state: SystemState<TlasCast> = SystemState::new(app.world_mut());
+    let ray = RayCast3d::new(vec3a(0.0, 0.0, 5.0), Dir3A::NEG_Z, f32::MAX);
+    let mut settle = |app: &mut App| {
+        let mut saw_pending = false;
+        let mut idle = 0;
+        for _ in 0..10_000 {
+            app.update();
+            let entity = app.world().entity(e);
+            if entity.contains::<PendingMeshBvh>() {
+                saw_pending = true;
+                idle = 0;
+            } else {
+                idle += 1;
+            }
+            if entity.contains::<MeshBvh>() {
+                let cast = state.get(app.world());
+                assert_eq!(cast.intersect_tlas(&ray).map(|(hit, _)| hit), Some(e));
+            }
+            if idle == 3 {
+                return saw_pending;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        panic!("Background build never finished");
+    };
+    let mesh_bvh = |app: &App| app.world().get::<MeshBvh>(e).unwrap().0.clone();
+    // moves every vertex somewhere else, far too much for a refit
+    let mut rng = ChaChaRng::seed_from_u64(3);
+    let mut shuffle = |app: &mut App, h_mesh: &Handle<Mesh>| {
+        let mut meshes = app.world_mut().resource_mut::<Assets<Mesh>>();
+        let mesh = meshes.get_mut(h_mesh).unwrap();
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION)
+        else {
+            unreachable!()
+        };
+        positions.shuffle(&mut rng);
+        mesh.clone()
+    };
+    let assert_built_from = |app: &App, mesh: &Mesh| {
+        let bvhs = app.world().resource::<Assets<Bvh>>();
+        let bvh = bvhs.get(&mesh_bvh(app)).unwrap();
+        let expected = Bvh::try_from_mesh(mesh).unwrap();
+        assert!(bvh.tris.iter().zip(&expected.tris).all(|(a, b)| [
+            a.vertex0, a.vertex1, a.vertex2
+        ] == [
+            b.vertex0, b.vertex1, b.vertex2
+        ]));
+        // built with the settings the entity was spawned with
+        assert_eq!(bvh.settings.max_leaf_size, 4);
+        bvh.tri_sources
+            .iter()
+            .map(|source| source.user_id)
+            .collect::<Vec<_>>()
+    };
+
+    assert!(settle(&mut app));
+    assert_built_from(&app, &source);
+    // the bvh remembers its settings and user ids, the entity doesn't need to
+    app.world_mut().entity_mut(e).remove::<BvhBuildSettings>();
+    let first = mesh_bvh(&app);
+    let tri_count = Bvh::try_from_mesh(&source).unwrap().tris.len() as u32;
+    let user_ids = (0..tri_count).collect::<Vec<_>>();
+    app.world_mut()
+        .resource_mut::<Assets<Bvh>>()
+        .get_mut(&first)
+        .unwrap()
+        .set_user_ids(user_ids.iter().copied());
+
+    // too degraded to refit, rebuilt in the background
+    let shuffled = shuffle(&mut app, &h_mesh);
+    assert!(settle(&mut app));
+    assert_ne!(mesh_bvh(&app), first);
+    assert_eq!(assert_built_from(&app, &shuffled), user_ids);
+
+    // swapped, then modified while building, the build starts over from the latest positions
+    let swapped = app.world_mut().resource_mut::<Assets<Mesh>>().add(source);
+    app.world_mut()
+        .entity_mut(e)
+        .insert(Mesh3d(swapped.clone()));
+    app.update();
+    assert!(app.world().entity(e).contains::<PendingMeshBvh>());
+    let shuffled = shuffle(&mut app, &swapped);
+    assert!(settle(&mut app));
+    assert_built_from(&app, &shuffled);
+}
+
+#[test]
+fn swapped_mesh_keeps_settings() {
+    let mut app = test_app();
+    let mut meshes = app.world_mut().resource_mut::<Assets<Mesh>>();
+    let cuboid = meshes.add(Cuboid::default());
+    let unloaded = meshes.reserve_handle();
+    let settings = BvhBuildSettings {
+        max_leaf_size: 4,
+        ..default()
+    };
+    let e = app
+        .world_mut()
+        .spawn((Mesh3d(cuboid), settings, SpawnMeshBvh))
+        .id();
+    app.update();
+    let first = app.world().get::<MeshBvh>(e).unwrap().0.clone();
+
+    // swapped for a mesh that isn't loaded yet, the settings are only known to the bvh
+    app.world_mut()
+        .entity_mut(e)
+        .remove::<BvhBuildSettings>()
+        .insert(Mesh3d(unloaded.clone()));
+    app.update();
+    assert_eq!(app.world().get::<MeshBvh>(e).unwrap().0, first);
+
+    let sphere = Sphere::new(1.0).mesh().ico(2).unwrap();
+    let tri_count = Bvh::try_from_mesh(&sphere).unwrap().tris.len();
+    app.world_mut()
+        .resource_mut::<Assets<Mesh>>()
+        .insert(&unloaded, sphere);
+    app.update();
+    let handle = app.world().get::<MeshBvh>(e).unwrap();
+    let bvh = app
+        .world()
+        .resource::<Assets<Bvh>>()
+        .get(&handle.0)
+        .unwrap();
+    assert_eq!(bvh.tris.len(), tri_count);
+    assert_eq!(bvh.settings.max_leaf_size, 4);
+}
+
+#[test]
+fn refit() {
+    // a wave over the sphere, the tree stays valid but isn't what a build would make
+    let wave = |v: Vec3A| v * (1.0 + 0.3 * (v.x * 4.0).sin());
+    let mesh = Sphere::new(1.0).mesh().ico(8).unwrap();
+    let points = golden_spiral(500);
+    for wide in [false, true] {
+        let mut bvh =
+            Bvh::try_from_mesh_with_settings(&mesh, BvhBuildSettings { wide, ..default() })
+                .unwrap();
+        let moved = bvh
+            .tris
+            .iter()
+            .map(|tri| Tri::new(wave(tri.vertex0), wave(tri.vertex1), wave(tri.vertex2)))
+            .collect::<Vec<_>>();
+        bvh.refit(&moved);
+        let fresh = Bvh::new(moved);
+
+        for (i, point) in points.iter().enumerate() {
+            let origin = *point * 3.0;
+            let target = points[(i * 13) % points.len()] * 0.5;
+            let ray = RayCast3d::new(origin, Dir3A::new(target - origin).unwrap(), f32::MAX);
+            let hit = ray.intersect_bvh(&bvh).expect("Refit Bvh missed");
+            let expected = ray.intersect_bvh(&fresh).unwrap();
+            // rays through a shared edge may report either triangle
+            assert!(
+                (hit.distance - expected.distance).abs() < 1e-5,
+                "wide: {wide}, {hit:?} {expected:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn refit_needs_rebuild() {
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+
+    let mesh = Sphere::new(1.0).mesh().ico(8).unwrap();
+    let mut bvh = Bvh::try_from_mesh(&mesh).unwrap();
+    let tris = bvh.tris.clone();
+    assert_eq!(bvh.refit_quality(), 1.0);
+    assert!(!bvh.needs_rebuild(1.5));
+
+    // moved as a whole, the tree is as good as it was
+    let offset = vec3a(5.0, -2.0, 1.0);
+    let moved = tris
+        .iter()
+        .map(|tri| {
+            Tri::new(
+                tri.vertex0 + offset,
+                tri.vertex1 + offset,
+                tri.vertex2 + offset,
+            )
+        })
+        .collect::<Vec<_>>();
+    bvh.refit(&moved);
+    assert!((bvh.refit_quality() - 1.0).abs() < 1e-3);
+    assert!(!bvh.needs_rebuild(1.5));
+
+    // each vertex drifts towards another one, the tree gets worse the further they go
+    let mut rng = ChaChaRng::seed_from_u64(11);
+    let mut targets = tris.clone();
+    targets.shuffle(&mut rng);
+    let mut flipped = None;
+    for step in 0..=10 {
+        let t = step as f32 / 10.0;
+        let blended = tris
+            .iter()
+            .zip(&targets)
+            .map(|(a, b)| {
+                Tri::new(
+                    a.vertex0.lerp(b.vertex0, t),
+                    a.vertex1.lerp(b.vertex1, t),
+                    a.vertex2.lerp(b.vertex2, t),
+                )
+            })
+            .collect::<Vec<_>>();
+        bvh.refit(&blended);
+        let needs_rebuild = bvh.needs_rebuild(1.5);
+        assert_eq!(needs_rebuild, bvh.refit_quality() > 1.5, "step {step}");
+        match flipped {
+            None if needs_rebuild => flipped = Some(step),
+            Some(_) => assert!(needs_rebuild, "step {step}"),
+            None => {}
+        }
+    }
+    assert!(flipped.is_some_and(|step| step > 0));
+    // a fresh build of the same triangles starts over
+    assert!(!Bvh::new(bvh.tris.clone()).needs_rebuild(1.5));
+}
+
+#[test]
+fn refit_from_mesh() {
+    let mesh = Sphere::new(1.0).mesh().ico(2).unwrap();
+    let mut bvh = Bvh::try_from_mesh(&mesh).unwrap();
+
+    let moved = mesh
+        .clone()
+        .transformed_by(Transform::from_xyz(0.0, 3.0, 0.0));
+    bvh.refit_from_mesh(&moved).unwrap();
+    let ray = RayCast3d::new(vec3a(0.0, 10.0, 0.0), Dir3A::NEG_Y, f32::MAX);
+    assert!((ray.intersect_bvh(&bvh).unwrap().distance - 6.0).abs() < 1e-5);
+
+    // a different topology can't be refit, the bvh is left as it was
+    let finer = Sphere::new(1.0).mesh().ico(3).unwrap();
+    assert_eq!(
+        bvh.refit_from_mesh(&finer),
+        Err(BvhBuildError::TriangleCountMismatch {
+            expected: bvh.tris.len(),
+            found: Bvh::try_from_mesh(&finer).unwrap().tris.len(),
+        })
+    );
+    assert!((ray.intersect_bvh(&bvh).unwrap().distance - 6.0).abs() < 1e-5);
+}
+
+#[test]
+fn mesh_bvh_refit_or_rebuild() {
+    let mut app = test_app();
+    let h_mesh = app
+        .world_mut()
+        .resource_mut::<Assets<Mesh>>()
+        .add(Sphere::new(1.0).mesh().ico(8).unwrap());
+    let e = app
+        .world_mut()
+        .spawn((Mesh3d(h_mesh.clone()), SpawnMeshBvh))
+        .id();
+    app.update();
+    let handle = app.world().get::<MeshBvh>(e).unwrap().0.clone();
+    let bvh = |app: &App| {
+        let bvh = app.world().resource::<Assets<Bvh>>().get(&handle).unwrap();
+        (bvh.build_cost, bvh.refit_quality(), bvh.tris[0].vertex0)
+    };
+    let move_vertices = |app: &mut App, f: &dyn Fn(usize, [f32; 3]) -> [f32; 3]| {
+        let mut meshes = app.world_mut().resource_mut::<Assets<Mesh>>();
+        let mesh = meshes.get_mut(&h_mesh).unwrap();
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION)
+        else {
+            unreachable!()
+        };
+        let old = positions.clone();
+        for (i, position) in positions.iter_mut().enumerate() {
+            *position = f(i, old[i]);
+        }
+        app.update();
+        app.update();
+    };
+    let (built, ..) = bvh(&app);
+
+    // a small move is refit, keeping the cost it was built with
+    move_vertices(&mut app, &|_, [x, y, z]| [x + 1.0, y, z * 1.1]);
+    let (cost, quality, vertex) = bvh(&app);
+    assert_eq!(cost, built);
+    // the threshold the helpers rebuild past
+    assert!(quality <= 1.5);
+    assert!(vertex.x > 0.5);
+
+    // each vertex moved to where another one was, far past the threshold, rebuilt in place
+    let meshes = app.world().resource::<Assets<Mesh>>();
+    let positions = meshes
+        .get(&h_mesh)
+        .unwrap()
+        .attribute(Mesh::ATTRIBUTE_POSITION)
+        .unwrap()
+        .as_float3()
+        .unwrap()
+        .to_vec();
+    move_vertices(&mut app, &|i, _| positions[(i * 7919) % positions.len()]);
+    assert_eq!(app.world().get::<MeshBvh>(e).unwrap().0, handle);
+    let (cost, quality, _) = bvh(&app);
+    assert_ne!(cost, built);
+    assert_eq!(quality, 1.0);
+}
+
+#[test]
+fn build_error_unsupported_topology() {
+    let mesh = test_mesh(PrimitiveTopology::LineList, vec![[0.0; 3]; 4], None);
+    assert_eq!(
+        Bvh::try_from_mesh(&mesh).unwrap_err(),
+        BvhBuildError::UnsupportedTopology(PrimitiveTopology::LineList)
+    );
+}
+
+#[test]
+fn build_error_missing_positions() {
+    let mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 1.0, 0.0]; 3]);
+    assert_eq!(
+        Bvh::try_from_mesh(&mesh).unwrap_err(),
+        BvhBuildError::MissingPositions
+    );
+}
+
+#[test]
+fn build_error_unsupported_position_format() {
+    // the positions attribute, with a format that can't hold a position
+    let attribute = MeshVertexAttribute {
+        format: VertexFormat::Float32,
+        ..Mesh::ATTRIBUTE_POSITION
+    };
+    let mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(attribute, VertexAttributeValues::Float32(vec![0.0; 3]));
+    assert_eq!(
+        Bvh::try_from_mesh(&mesh).unwrap_err(),
+        BvhBuildError::UnsupportedPositionFormat(VertexFormat::Float32)
+    );
+}
+
+#[test]
+fn build_error_index_out_of_bounds() {
+    let positions = vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+    let mesh = test_mesh(
+        PrimitiveTopology::TriangleList,
+        positions,
+        Some(vec![0, 1, 2, 2, 1, 3]),
+    );
+    let expected = BvhBuildError::IndexOutOfBounds {
+        index: 3,
+        vertex_count: 3,
+    };
+    assert_eq!(Bvh::try_from_mesh(&mesh).unwrap_err(), expected);
+    // sub meshes fail the same way
+    let sphere = Sphere::new(1.0).mesh().ico(1).unwrap();
+    let sub_meshes = [&sphere, &mesh].map(|mesh| BvhSubMesh { mesh, user_id: 0 });
+    assert_eq!(
+        Bvh::try_from_sub_meshes(&sub_meshes, default()).unwrap_err(),
+        expected
+    );
+}
+
+#[test]
+fn build_error_no_triangles() {
+    let positions = vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+    // too few indices or vertices for a triangle, and a strip of only degenerate triangles
+    for (topology, positions, indices) in [
+        (
+            PrimitiveTopology::TriangleList,
+            positions.clone(),
+            Some(vec![0, 1]),
+        ),
+        (
+            PrimitiveTopology::TriangleList,
+            positions.clone(),
+            Some(vec![]),
+        ),
+        (
+            PrimitiveTopology::TriangleList,
+            positions[..2].to_vec(),
+            None,
+        ),
+        (
+            PrimitiveTopology::TriangleStrip,
+            positions,
+            Some(vec![0, 1, 1, 2]),
+        ),
+    ] {
+        let mesh = test_mesh(topology, positions, indices);
+        assert_eq!(
+            Bvh::try_from_mesh(&mesh).unwrap_err(),
+            BvhBuildError::NoTriangles,
+            "{topology:?}"
+        );
+    }
+}
+
+#[test]
+fn triangle_strips() {
+    // a row of quads in the xy plane, bottom and top vertices alternating along x
+    let positions = (0..=4)
+        .flat_map(|x| [[x as f32, 0.0, 0.0], [x as f32, 1.0, 0.0]])
+        .collect::<Vec<_>>();
+    // every other triangle of a strip is flipped to wind the same way as the first, restarts
+    // begin a new strip
+    let list_indices = |indices: &[u32]| {
+        indices
+            .split(|i| *i == u32::MAX)
+            .flat_map(|strip| strip.windows(3).enumerate())
+            .filter(|(_, w)| w[0] != w[1] && w[1] != w[2] && w[0] != w[2])
+            .flat_map(|(i, w)| match i % 2 {
+                0 => [w[0], w[1], w[2]],
+                _ => [w[1], w[0], w[2]],
+            })
+            .collect::<Vec<_>>()
+    };
+    let unindexed = |indices: &[u32]| {
+        indices
+            .iter()
+            .map(|i| positions[*i as usize])
+            .collect::<Vec<_>>()
+    };
+
+    // rays through the middle of every triangle, from both sides
+    let rays = (0..4)
+        .flat_map(|x| [(x as f32 + 0.25, 0.25), (x as f32 + 0.75, 0.75)])
+        .flat_map(|(x, y)| {
+            [(2.0, Dir3A::NEG_Z), (-2.0, Dir3A::Z)]
+                .map(|(z, dir)| RayCast3d::new(vec3a(x, y, z), dir, f32::MAX))
+        })
+        .collect::<Vec<_>>();
+    let hits = |mesh: &Mesh| {
+        let bvh = Bvh::try_from_mesh(mesh).unwrap();
+        rays.iter()
+            .map(|ray| ray.intersect_bvh(&bvh))
+            .collect::<Vec<_>>()
+    };
+    let assert_same = |strip: &Mesh, list: &Mesh, indexed: bool| {
+        for (i, (a, b)) in hits(strip).into_iter().zip(hits(list)).enumerate() {
+            let context = format!("{:?}, ray {i}", strip.indices());
+            assert_eq!(a.is_some(), b.is_some(), "{context}");
+            let (Some(a), Some(b)) = (a, b) else {
+                continue;
+            };
+            assert_eq!(a.distance, b.distance, "{context}");
+            assert_eq!(a.tri_index, b.tri_index, "{context}");
+            assert_eq!(a.front_face, b.front_face, "{context}");
+            assert_eq!(a.normal, b.normal, "{context}");
+            // counter clockwise seen from -z
+            assert_eq!(a.normal, Vec3A::NEG_Z, "{context}");
+            assert_eq!(a.front_face, rays[i].direction.z > 0.0, "{context}");
+            if indexed {
+                assert_eq!(a.source, b.source, "{context}");
+            }
+        }
+    };
+
+    let strip = (0..10).collect::<Vec<u32>>();
+    let restarted = [0, 1, 2, 3, u32::MAX, 4, 5, 6, 7];
+    // degenerate triangles stitching two strips, keeping the winding of the second
+    let stitched = [0, 1, 2, 3, 3, 4, 4, 5, 6, 7];
+    for indices in [&strip[..], &restarted, &stitched] {
+        let list = list_indices(indices);
+        assert_same(
+            &test_mesh(
+                PrimitiveTopology::TriangleStrip,
+                positions.clone(),
+                Some(indices.to_vec()),
+            ),
+            &test_mesh(
+                PrimitiveTopology::TriangleList,
+                positions.clone(),
+                Some(list.clone()),
+            ),
+            true,
+        );
+        // the same triangles without indices
+        assert_same(
+            &test_mesh(
+                PrimitiveTopology::TriangleStrip,
+                positions.clone(),
+                Some(indices.to_vec()),
+            ),
+            &test_mesh(PrimitiveTopology::TriangleList, unindexed(&list), None),
+            false,
+        );
+    }
+    assert_same(
+        &test_mesh(PrimitiveTopology::TriangleStrip, unindexed(&strip), None),
+        &test_mesh(
+            PrimitiveTopology::TriangleList,
+            positions.clone(),
+            Some(list_indices(&strip)),
+        ),
+        false,
+    );
+
+    // the gap left by the restart is empty, u16 restarts too
+    let u16_restarted = test_mesh(PrimitiveTopology::TriangleStrip, positions.clone(), None)
+        .with_inserted_indices(Indices::U16(
+            restarted
+                .map(|i| if i == u32::MAX { u16::MAX } else { i as u16 })
+                .to_vec(),
+        ));
+    let bvh = Bvh::try_from_mesh(&u16_restarted).unwrap();
+    assert_eq!(bvh.tris.len(), 4);
+    let gap = RayCast3d::new(vec3a(1.5, 0.5, 2.0), Dir3A::NEG_Z, f32::MAX);
+    assert!(gap.intersect_bvh(&bvh).is_none());
+    assert_eq!(
+        bvh.tri_sources
+            .iter()
+            .map(|source| source.vertices)
+            .collect::<Vec<_>>(),
+        [[0, 1, 2], [2, 1, 3], [4, 5, 6], [6, 5, 7]]
+    );
+
+    // restart values are only special in strips
+    let list = test_mesh(
+        PrimitiveTopology::TriangleList,
+        positions,
+        Some(vec![0, 1, 2, 3, u32::MAX, 4]),
+    );
+    assert_eq!(
+        Bvh::try_from_mesh(&list).unwrap_err(),
+        BvhBuildError::IndexOutOfBounds {
+            index: u32::MAX as usize,
+            vertex_count: 10,
+        }
+    );
+}
+
+#[test]
+fn spatial_splits() {
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+
+    // long thin triangles along the diagonal of a cube, their boxes overlap almost entirely
+    let mut rng = ChaChaRng::seed_from_u64(5);
+    let tris = (0..500)
+        .map(|_| {
+            let offset = vec3a(
+                rng.random_range(-10.0..10.0),
+                rng.random_range(-10.0..10.0),
+                rng.random_range(-10.0..10.0),
+            );
+            let start = Vec3A::splat(-20.0) + offset;
+            let end = Vec3A::splat(20.0) + offset;
+            Tri::new(start, end, end + vec3a(0.5, -0.5, 0.0))
+        })
+        .collect::<Vec<_>>();
+    let bvh = Bvh::new(tris.clone());
+    let sbvh = Bvh::new_with_settings(
+        tris.clone(),
+        BvhBuildSettings {
+            spatial_splits: true,
+            ..default()
+        },
+    );
+
+    // triangles are referenced more than once, but no more than the budget allows
+    assert!(sbvh.triangle_indexs.len() > tris.len());
+    assert!(sbvh.triangle_indexs.len() <= tris.len() * 2);
+    let mut referenced = sbvh.triangle_indexs.clone();
+    referenced.sort_unstable();
+    referenced.dedup();
+    assert_eq!(referenced.len(), tris.len());
+    // and the nodes are tighter for it
+    assert!(sbvh.sah_cost() < bvh.sah_cost());
+
+    // the same closest hits as the regular build
+    let points = golden_spiral(400);
+    let mut hits = 0;
+    for (i, point) in points.iter().enumerate() {
+        let origin = *point * 60.0;
+        let target = points[(i * 17) % points.len()] * 15.0;
+        let ray = RayCast3d::new(origin, Dir3A::new(target - origin).unwrap(), f32::MAX);
+        let expected = ray.intersect_bvh(&bvh);
+        let hit = ray.intersect_bvh(&sbvh);
+        assert_eq!(hit.is_some(), expected.is_some(), "ray {i}");
+        if let (Some(hit), Some(expected)) = (hit, expected) {
+            assert!((hit.distance - expected.distance).abs() < 1e-4, "ray {i}");
+            hits += 1;
+        }
+    }
+    assert!(hits > points.len() / 4, "{hits}");
+}
+
+#[test]
+fn linear_build() {
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+
+    let mut rng = ChaChaRng::seed_from_u64(9);
+    let mut random_vec3 = || {
+        vec3a(
+            rng.random_range(-1.0..=1.0),
+            rng.random_range(-1.0..=1.0),
+            rng.random_range(-1.0..=1.0),
+        )
+    };
+    let tris = (0..5_000)
+        .map(|_| {
+            let v0 = random_vec3() * 20.0;
+            Tri::new(v0, v0 + random_vec3(), v0 + random_vec3())
+        })
+        .collect::<Vec<_>>();
+    let sah = Bvh::new(tris.clone());
+    for max_leaf_size in [u32::MAX, 4] {
+        let linear = Bvh::new_with_settings(
+            tris.clone(),
+            BvhBuildSettings {
+                linear: true,
+                max_leaf_size,
+                ..default()
+            },
+        );
+        assert!(linear.nodes.len() > 1);
+
+        // a fan of rays from outside the triangles, through all of them
+        let origin = vec3a(0.0, 5.0, 60.0);
+        let mut hits = 0;
+        for x in -40..=40 {
+            for y in -40..=40 {
+                let target = vec3a(x as f32 * 0.5, y as f32 * 0.5, 0.0);
+                let ray = RayCast3d::new(origin, Dir3A::new(target - origin).unwrap(), f32::MAX);
+                let hit = ray.intersect_bvh(&linear);
+                let expected = ray.intersect_bvh(&sah);
+                assert_eq!(hit.is_some(), expected.is_some(), "{target}");
+                if let (Some(hit), Some(expected)) = (hit, expected) {
+                    assert_eq!(hit.distance, expected.distance, "{target}");
+                    assert_eq!(hit.tri_index, expected.tri_index, "{target}");
+                    hits += 1;
+                }
+            }
+        }
+        assert!(hits > 1000, "{hits}");
+    }
+}
+
+#[test]
+fn optimize() {
+    use rand::prelude::*;
+    use rand_chacha::ChaChaRng;
+
+    let mesh = Sphere::new(1.0).mesh().ico(10).unwrap();
+    let mut rng = ChaChaRng::seed_from_u64(13);
+    let mut wobble = || vec3a(rng.random(), rng.random(), rng.random()) * 0.4;
+    for (name, settings) in [
+        (
+            "linear",
+            BvhBuildSettings {
+                linear: true,
+                ..default()
+            },
+        ),
+        (
+            "refit",
+            BvhBuildSettings {
+                wide: true,
+                ..default()
+            },
+        ),
+    ] {
+        let mut bvh = Bvh::try_from_mesh_with_settings(&mesh, settings).unwrap();
+        if name == "refit" {
+            // stretched out of shape, so the tree has something to fix
+            let moved = bvh
+                .tris
+                .iter()
+                .map(|tri| {
+                    let offset = wobble() + vec3a(tri.centroid.y * 3.0, 0.0, 0.0);
+                    Tri::new(
+                        tri.vertex0 + offset,
+                        tri.vertex1 + offset,
+                        tri.vertex2 + offset,
+                    )
+                })
+                .collect::<Vec<_>>();
+            bvh.refit(&moved);
+        }
+
+        let points = golden_spiral(300);
+        let rays = points
+            .iter()
+            .enumerate()
+            .map(|(i, point)| {
+                let origin = *point * 5.0;
+                let target = points[(i * 11) % points.len()] * 0.8;
+                RayCast3d::new(origin, Dir3A::new(target - origin).unwrap(), f32::MAX)
+            })
+            .collect::<Vec<_>>();
+        let before = rays
+            .iter()
+            .map(|ray| ray.intersect_bvh(&bvh))
+            .collect::<Vec<_>>();
+
+        let report = bvh.optimize(8);
+        assert!(report.rotations > 0, "{name}: {report:?}");
+        assert!(report.sah_after <= report.sah_before, "{name}: {report:?}");
+        assert_eq!(report.sah_after, bvh.sah_cost(), "{name}");
+
+        // every triangle still in exactly one leaf, children after their parents
+        let mut found = vec![0; bvh.tris.len()];
+        let mut stack = vec![0];
+        while let Some(node_idx) = stack.pop() {
+            let node = &bvh.nodes[node_idx];
+            if node.is_leaf() {
+                for i in 0..node.tri_count {
+                    found[bvh.triangle_indexs[(node.left_first + i) as usize]] += 1;
+                }
+            } else {
+                assert!(node.left_first as usize > node_idx, "{name}");
+                assert!((node.left_first as usize) + 1 < bvh.nodes.len(), "{name}");
+                stack.push(node.left_first as usize);
+                stack.push(node.left_first as usize + 1);
+            }
+        }
+        assert!(found.iter().all(|count| *count == 1), "{name}");
+
+        for (ray, before) in rays.iter().zip(before) {
+            let after = ray.intersect_bvh(&bvh);
+            assert_eq!(
+                after.map(|hit| (hit.distance, hit.tri_index)),
+                before.map(|hit| (hit.distance, hit.tri_index)),
+                "{name}"
+            );
+        }
+    }
+}
+
+#[test]
+fn baked_bvhs_format() {
+    let sphere = Bvh::try_from_mesh(&Sphere::new(1.0).mesh().ico(1).unwrap()).unwrap();
+    let cube = Bvh::try_from_mesh(&Cuboid::default().mesh().build()).unwrap();
+    let bytes = baked_bvhs_to_bytes([("Mesh0/Primitive0", &sphere), ("Mesh1/Primitive0", &cube)]);
+    // header and the first label, then the first bvh's length, see `BakedBvhsSaver`
+    let label_start = 16;
+    let bvh_start = label_start + "Mesh0/Primitive0".len() + 4;
+    let corrupt = |offset: usize, value: u32| {
+        let mut bytes = bytes.clone();
+        bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        baked_bvhs_from_bytes(&bytes)
+    };
+
+    let loaded = baked_bvhs_from_bytes(&bytes).unwrap();
+    assert_eq!(loaded.len(), 2);
+    for ((label, bvh), (expected_label, expected)) in loaded
+        .iter()
+        .zip([("Mesh0/Primitive0", &sphere), ("Mesh1/Primitive0", &cube)])
+    {
+        assert_eq!(label, expected_label);
+        assert_eq!(bvh.to_bytes(), expected.to_bytes());
+    }
+
+    for len in 0..bytes.len() {
+        let result = baked_bvhs_from_bytes(&bytes[..len]);
+        if len < 8 {
+            assert!(
+                matches!(
+                    result,
+                    Err(BvhBakeError::Format(BvhFormatError::InvalidMagic))
+                ),
+                "{len}"
+            );
+        } else {
+            assert!(
+                matches!(
+                    result,
+                    Err(BvhBakeError::Format(BvhFormatError::SizeMismatch { found, .. }))
+                        if found == len
+                ),
+                "{len}"
+            );
+        }
+    }
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert!(matches!(
+        baked_bvhs_from_bytes(&longer),
+        Err(BvhBakeError::Format(BvhFormatError::SizeMismatch { .. }))
+    ));
+    // a label length far past the end of the file
+    assert!(matches!(
+        corrupt(label_start - 4, u32::MAX),
+        Err(BvhBakeError::Format(BvhFormatError::SizeMismatch { found, .. }))
+            if found == bytes.len()
+    ));
+
+    assert!(matches!(
+        corrupt(0, u32::from_le_bytes(*b"NOPE")),
+        Err(BvhBakeError::Format(BvhFormatError::InvalidMagic))
+    ));
+    assert!(matches!(
+        corrupt(4, BVH_FORMAT_VERSION + 1),
+        Err(BvhBakeError::Format(BvhFormatError::UnsupportedVersion(_)))
+    ));
+    assert!(matches!(
+        corrupt(label_start, u32::MAX),
+        Err(BvhBakeError::InvalidLabel(_))
+    ));
+    // the baked bvh itself is checked too
+    assert!(matches!(
+        corrupt(bvh_start, u32::from_le_bytes(*b"NOPE")),
+        Err(BvhBakeError::Format(BvhFormatError::InvalidMagic))
+    ));
+}
+
+#[test]
+fn scene_bvhs_use_baked() {
+    let mut app = test_app();
+    let world = app.world_mut();
+    // path handles carry the mesh's label in the glTF, the meshes themselves are added by hand
+    let server = world.resource::<AssetServer>();
+    let baked_mesh = server.load::<Mesh>("scene.gltf#Mesh0/Primitive0");
+    let other_mesh = server.load::<Mesh>("scene.gltf#Mesh1/Primitive0");
+    let mut meshes = world.resource_mut::<Assets<Mesh>>();
+    meshes.insert(&baked_mesh, Cuboid::default().mesh().build());
+    meshes.insert(&other_mesh, Cuboid::default().mesh().build());
+
+    let baked_bvh = world
+        .resource_mut::<Assets<Bvh>>()
+        .add(Bvh::try_from_mesh(&Cuboid::default().mesh().build()).unwrap());
+    let mut bvhs = bevy::platform::collections::HashMap::default();
+    bvhs.insert("Mesh0/Primitive0".to_string(), baked_bvh.clone());
+    let baked = world
+        .resource_mut::<Assets<BakedBvhs>>()
+        .add(BakedBvhs { bvhs });
+
+    let mut children = Vec::new();
+    let root = world
+        .spawn((SceneRoot::default(), SpawnSceneBvhs, BakedSceneBvhs(baked)))
+        .with_children(|parent| {
+            children.push(parent.spawn(Mesh3d(baked_mesh.clone())).id());
+            children.push(parent.spawn(Mesh3d(other_mesh)).id());
+            // baked with other settings than these, so it's rebuilt
+            children.push(
+                parent
+                    .spawn((
+                        Mesh3d(baked_mesh),
+                        BvhBuildSettings {
+                            max_leaf_size: 1,
+                            ..default()
+                        },
+                    ))
+                    .id(),
+            );
+        })
+        .id();
+    app.update();
+
+    assert!(!app.world().entity(root).contains::<SpawnSceneBvhs>());
+    let h_bvhs = children
+        .iter()
+        .map(|e| app.world().get::<MeshBvh>(*e).unwrap().0.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(h_bvhs[0], baked_bvh);
+    assert_ne!(h_bvhs[1], baked_bvh);
+    assert_ne!(h_bvhs[2], baked_bvh);
+    let bvhs = app.world().resource::<Assets<Bvh>>();
+    assert_eq!(bvhs.get(&h_bvhs[1]).unwrap().tris.len(), 12);
+    assert_eq!(bvhs.get(&h_bvhs[2]).unwrap().settings.max_leaf_size, 1);
+}
+
+#[test]
+fn hit_to_world_and_interpolate() {
+    use bevy::ecs::system::SystemState;
+
+    // one triangle in the plane x + y = 1, facing (1, 1, 0)
+    let corners = [
+        vec3a(1.0, 0.0, 0.0),
+        vec3a(0.0, 1.0, 0.0),
+        vec3a(0.5, 0.5, 1.0),
+    ];
+    let mesh = test_mesh(
+        PrimitiveTopology::TriangleList,
+        corners.iter().map(Vec3A::to_array).collect(),
+        None,
+    )
+    .with_inserted_attribute(
+        Mesh::ATTRIBUTE_UV_0,
+        vec![[0.0f32, 0.0], [1.0, 0.0], [0.0, 1.0]],
+    )
+    .with_inserted_attribute(
+        Mesh::ATTRIBUTE_NORMAL,
+        vec![[1.0f32, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+    );
+    let bvh = Bvh::try_from_mesh(&mesh).unwrap();
+    // stretched along y the plane becomes x + y / 4 = 1, scaling the normal would give (1, 4, 0)
+    let transform = Transform::from_scale(vec3(1., 4., 1.));
+    let global = GlobalTransform::from(transform);
+    let normal = vec3a(4.0, 1.0, 0.0).normalize();
+    let weights = [0.5, 0.25, 0.25];
+    let point = corners
+        .iter()
+        .zip(weights)
+        .map(|(corner, weight)| global.affine().transform_point3a(*corner) * weight)
+        .sum::<Vec3A>();
+    let ray = RayCast3d::new(point + normal * 3.0, Dir3A::new(-normal).unwrap(), 10.0);
+
+    let (local_ray, dir_scale) = ray.to_local(&global);
+    let hit = local_ray
+        .intersect_bvh(&bvh)
+        .unwrap()
+        .to_world(&global, dir_scale);
+    assert!((hit.distance - 3.0).abs() < 1e-4, "{hit:?}");
+    assert!(hit.point.distance(point) < 1e-4, "{hit:?}");
+    assert!(hit.normal.distance(normal) < 1e-5, "{hit:?}");
+    assert!(hit.front_face);
+
+    // attributes are interpolated with the hit's barycentrics, in mesh space
+    let uv = hit.interpolate(&mesh, Mesh::ATTRIBUTE_UV_0).unwrap();
+    assert!(uv.distance(vec4(0.25, 0.25, 0.0, 0.0)) < 1e-5, "{uv}");
+    let vertex_normal = hit.interpolate(&mesh, Mesh::ATTRIBUTE_NORMAL).unwrap();
+    assert!(
+        vertex_normal.distance(vec4(0.5, 0.25, 0.25, 0.0)) < 1e-5,
+        "{vertex_normal}"
+    );
+    assert!(hit.interpolate(&mesh, Mesh::ATTRIBUTE_TANGENT).is_none());
+    // a bvh not built from a mesh has nothing to interpolate
+    let tris = Bvh::new(bvh.tris.clone());
+    let tris_hit = local_ray.intersect_bvh(&tris).unwrap();
+    assert!(tris_hit.interpolate(&mesh, Mesh::ATTRIBUTE_UV_0).is_none());
+
+    // the tlas converts its hits the same way
+    let mut app = test_app();
+    let handle = app.world_mut().resource_mut::<Assets<Bvh>>().add(bvh);
+    let entity = app.world_mut().spawn((MeshBvh(handle), transform)).id();
+    app.update();
+    app.update();
+    let mut state: SystemState<TlasCast> = SystemState::new(app.world_mut());
+    let cast = state.get(app.world());
+    let (e, tlas_hit) = cast.intersect_tlas(&ray).unwrap();
+    assert_eq!(e, entity);
+    assert!((tlas_hit.distance - hit.distance).abs() < 1e-5);
+    assert!(tlas_hit.point.distance(hit.point) < 1e-5);
+    assert!(tlas_hit.normal.distance(normal) < 1e-5);
+}
+
+#[test]
+fn sub_mesh_sources() {
+    let sphere = Sphere::new(1.0).mesh().ico(2).unwrap();
+    // far enough apart that the rays aimed at one never reach the other
+    let cube = Cuboid::default()
+        .mesh()
+        .build()
+        .translated_by(vec3(30.0, 0.0, 0.0));
+    let sub_meshes = [
+        BvhSubMesh {
+            mesh: &sphere,
+            user_id: 3,
+        },
+        BvhSubMesh {
+            mesh: &cube,
+            user_id: 7,
+        },
+    ];
+    let mut bvh = Bvh::try_from_sub_meshes(&sub_meshes, default()).unwrap();
+
+    // every hit points back at the mesh and vertices it was built from
+    let check = |bvh: &Bvh, offset: Vec3A| {
+        for (sub_mesh, center) in [(0, Vec3A::ZERO), (1, vec3a(30.0, 0.0, 0.0))] {
+            for dir in golden_spiral(64) {
+                let ray = RayCast3d::new(
+                    center + offset + dir * 10.0,
+                    Dir3A::new(-dir).unwrap(),
+                    20.0,
+                );
+                let hit = ray.intersect_bvh(bvh).unwrap();
+                let source = hit.source.unwrap();
+                assert_eq!(source.sub_mesh, sub_mesh, "{dir}");
+                assert_eq!(source.user_id, sub_meshes[sub_mesh as usize].user_id);
+                let Some(VertexAttributeValues::Float32x3(positions)) = sub_meshes
+                    [sub_mesh as usize]
+                    .mesh
+                    .attribute(Mesh::ATTRIBUTE_POSITION)
+                else {
+                    unreachable!()
+                };
+                let tri = bvh.tris[hit.tri_index];
+                for (vertex, tri_vertex) in
+                    source
+                        .vertices
+                        .iter()
+                        .zip([tri.vertex0, tri.vertex1, tri.vertex2])
+                {
+                    let position = Vec3A::from(positions[*vertex as usize]) + offset;
+                    assert!(position.distance(tri_vertex) < 1e-5, "{dir}");
+                }
+            }
+        }
+    };
+    check(&bvh, Vec3A::ZERO);
+
+    // refitting moves the triangles but keeps where they came from
+    let offset = vec3a(0.0, 10.0, 0.0);
+    let moved = bvh
+        .tris
+        .iter()
+        .map(|tri| {
+            Tri::new(
+                tri.vertex0 + offset,
+                tri.vertex1 + offset,
+                tri.vertex2 + offset,
+            )
+        })
+        .collect::<Vec<_>>();
+    bvh.refit(&moved);
+    check(&bvh, offset);
+}
+
+#[test]
+fn mesh_bvh_refit_keeps_user_ids() {
+    use bevy::ecs::system::SystemState;
+
+    let mut app = test_app();
+    let mesh = app
+        .world_mut()
+        .resource_mut::<Assets<Mesh>>()
+        .add(Cuboid::default());
+    let entity = app
+        .world_mut()
+        .spawn((Mesh3d(mesh.clone()), SpawnMeshBvh))
+        .id();
+    app.update();
+    let handle = app.world().get::<MeshBvh>(entity).unwrap().0.clone();
+    // ids set by game logic, one per triangle
+    for (i, source) in app
+        .world_mut()
+        .resource_mut::<Assets<Bvh>>()
+        .get_mut(&handle)
+        .unwrap()
+        .tri_sources
+        .iter_mut()
+        .enumerate()
+    {
+        source.user_id = i as u32 + 1;
+    }
+
+    // a small move is refit in place, the sources come from the mesh again
+    let offset = vec3(0.0, 0.25, 0.0);
+    let mut meshes = app.world_mut().resource_mut::<Assets<Mesh>>();
+    let moved = meshes.get(&mesh).unwrap().clone().translated_by(offset);
+    *meshes.get_mut(&mesh).unwrap() = moved;
+    app.update();
+    app.update();
+    assert_eq!(app.world().get::<MeshBvh>(entity).unwrap().0, handle);
+    let bvh = app.world().resource::<Assets<Bvh>>().get(&handle).unwrap();
+    assert!((bvh.nodes[0].aabb().min.y + 0.25).abs() < 1e-5);
+    for (i, source) in bvh.tri_sources.iter().enumerate() {
+        assert_eq!(source.user_id, i as u32 + 1);
+    }
+
+    let mut state: SystemState<TlasCast> = SystemState::new(app.world_mut());
+    let cast = state.get(app.world());
+    for dir in golden_spiral(64) {
+        let center = Vec3A::from(offset);
+        let ray = RayCast3d::new(center + dir * 10.0, Dir3A::new(-dir).unwrap(), 20.0);
+        let (e, hit) = cast.intersect_tlas(&ray).unwrap();
+        assert_eq!(e, entity);
+        assert_eq!(hit.source.unwrap().user_id, hit.tri_index as u32 + 1);
+    }
+}
+
+#[test]
+fn cull_modes() {
+    // counter clockwise seen from +z, so its front faces +z
+    let tri = Tri::new(
+        vec3a(-1.0, -1.0, 0.0),
+        vec3a(1.0, -1.0, 0.0),
+        vec3a(0.0, 1.0, 0.0),
+    );
+    let front = RayCast3d::new(vec3a(0.0, 0.0, 2.0), Dir3A::NEG_Z, 10.0);
+    let back = RayCast3d::new(vec3a(0.0, 0.0, -2.0), Dir3A::Z, 10.0);
+    let cube = Bvh::try_from_mesh(&Cuboid::default().mesh().build()).unwrap();
+    let outside = RayCast3d::new(vec3a(0.1, 0.2, 3.0), Dir3A::NEG_Z, 10.0);
+
+    for watertight in [false, true] {
+        for (cull_mode, hits_front, hits_back) in [
+            (CullMode::None, true, true),
+            (CullMode::Back, true, false),
+            (CullMode::Front, false, true),
+        ] {
+            let settings = RayCastSettings {
+                cull_mode,
+                watertight,
+                ..default()
+            };
+            let name = format!("{cull_mode:?} (watertight: {watertight})");
+            let front_hit = front.intersect_triangle_with_settings(&tri, 0, &settings);
+            assert_eq!(front_hit.is_some(), hits_front, "{name}");
+            if let Some(hit) = front_hit {
+                assert!(hit.front_face, "{name}");
+                assert!((hit.distance - 2.0).abs() < 1e-5, "{name}");
+                assert!(hit.normal.distance(Vec3A::Z) < 1e-5, "{name}");
+            }
+            let back_hit = back.intersect_triangle_with_settings(&tri, 0, &settings);
+            assert_eq!(back_hit.is_some(), hits_back, "{name}");
+            if let Some(hit) = back_hit {
+                assert!(!hit.front_face, "{name}");
+                assert!((hit.distance - 2.0).abs() < 1e-5, "{name}");
+                assert!(hit.normal.distance(Vec3A::Z) < 1e-5, "{name}");
+            }
+
+            // culled faces are skipped, the cast goes on through the cube
+            let hit = outside
+                .intersect_bvh_with_settings(&cube, &settings)
+                .unwrap();
+            let (distance, front_face) = match cull_mode {
+                CullMode::Front => (3.5, false),
+                _ => (2.5, true),
+            };
+            assert!((hit.distance - distance).abs() < 1e-5, "{name}");
+            assert_eq!(hit.front_face, front_face, "{name}");
+            // and from inside only back faces can be hit
+            let inside = RayCast3d::new(Vec3A::ZERO, Dir3A::X, 10.0)
+                .intersect_bvh_with_settings(&cube, &settings);
+            assert_eq!(inside.is_some(), cull_mode != CullMode::Back, "{name}");
+        }
+    }
+}
+
+#[test]
+fn any_hit_max_distance() {
+    use bevy::ecs::system::SystemState;
+
+    let mesh = Sphere::new(1.0).mesh().ico(3).unwrap();
+    for wide in [false, true] {
+        let bvh = Bvh::try_from_mesh_with_settings(&mesh, BvhBuildSettings { wide, ..default() })
+            .unwrap();
+        for dir in golden_spiral(64) {
+            let origin = dir * 3.0 + vec3a(0.01, -0.02, 0.03);
+            let direction = Dir3A::new(-dir).unwrap();
+            let closest = RayCast3d::new(origin, direction, 10.0)
+                .intersect_bvh(&bvh)
+                .unwrap()
+                .distance;
+
+            // the occluder is just out of reach
+            let short = RayCast3d::new(origin, direction, closest - 0.01);
+            assert!(short.any_hit_bvh(&bvh).is_none(), "{dir} (wide: {wide})");
+            assert!(!short.occluded(&bvh), "{dir} (wide: {wide})");
+            // and just in reach
+            let past = RayCast3d::new(origin, direction, closest + 0.01);
+            let hit = past.any_hit_bvh(&bvh).unwrap();
+            assert!(hit.distance <= past.max, "{dir} (wide: {wide})");
+            assert!(past.occluded(&bvh), "{dir} (wide: {wide})");
+        }
+    }
+
+    // distances are in world space for a scaled instance
+    let mut app = test_app();
+    let handle = app
+        .world_mut()
+        .resource_mut::<Assets<Bvh>>()
+        .add(Bvh::try_from_mesh(&mesh).unwrap());
+    let entity = app
+        .world_mut()
+        .spawn((MeshBvh(handle), Transform::from_scale(Vec3::splat(2.0))))
+        .id();
+    app.update();
+    app.update();
+    let mut state: SystemState<TlasCast> = SystemState::new(app.world_mut());
+    let cast = state.get(app.world());
+    let origin = vec3a(0.1, 0.2, 5.0);
+    let (_, closest) = cast
+        .intersect_tlas(&RayCast3d::new(origin, Dir3A::NEG_Z, 10.0))
+        .unwrap();
+    assert!((closest.distance - 3.0).abs() < 0.1);
+
+    let short = RayCast3d::new(origin, Dir3A::NEG_Z, closest.distance - 0.01);
+    assert!(cast.any_hit_tlas(&short).is_none());
+    assert!(!cast.occluded(&short));
+    let past = RayCast3d::new(origin, Dir3A::NEG_Z, closest.distance + 0.01);
+    let (e, hit) = cast.any_hit_tlas(&past).unwrap();
+    assert_eq!(e, entity);
+    assert!((hit.distance - closest.distance).abs() < 1e-4);
+    assert!(hit.point.distance(closest.point) < 1e-4);
+    assert!(cast.occluded(&past));
+    // with front faces culled the far side is the only occluder
+    let settings = RayCastSettings {
+        cull_mode: CullMode::Front,
+        ..default()
+    };
+    assert!(cast.any_hit_tlas_with_settings(&past, &settings).is_none());
+    let through = RayCast3d::new(origin, Dir3A::NEG_Z, 10.0);
+    let (_, hit) = cast
+        .any_hit_tlas_with_settings(&through, &settings)
+        .unwrap();
+    assert!(!hit.front_face);
+    assert!(hit.distance > closest.distance + 3.0);
+}
+
+#[test]
+fn bvh_filters() {
+    use bevy::platform::collections::{HashMap, HashSet};
+
+    let settings = RayCastSettings::default();
+    let cube = Cuboid::default().mesh().build();
+    let ray = RayCast3d::new(vec3a(0.1, 0.2, 3.0), Dir3A::NEG_Z, 10.0);
+    for wide in [false, true] {
+        let bvh = Bvh::try_from_mesh_with_settings(&cube, BvhBuildSettings { wide, ..default() })
+            .unwrap();
+        // rejecting the near face goes on to the far one
+        let near = ray.intersect_bvh(&bvh).unwrap();
+        let far = bvh
+            .intersect_filtered(&ray, &settings, |hit| hit.tri_index != near.tri_index)
+            .unwrap();
+        assert!((near.distance - 2.5).abs() < 1e-5, "wide: {wide}");
+        assert!((far.distance - 3.5).abs() < 1e-5, "wide: {wide}");
+        assert!(!far.front_face);
+
+        let any = bvh
+            .any_hit_filtered(&ray, &settings, |hit| !hit.front_face)
+            .unwrap();
+        assert!((any.distance - 3.5).abs() < 1e-5, "wide: {wide}");
+        assert!(bvh.any_hit_filtered(&ray, &settings, |_| false).is_none());
+
+        let all = bvh.intersect_all_filtered(&ray, None, &settings, |hit| !hit.front_face);
+        assert_eq!(all.len(), 1, "wide: {wide}");
+        assert_eq!(all[0].tri_index, far.tri_index);
+    }
+
+    // with spatial splits the closest and any hit filters can see a triangle more than once,
+    // the all hits filter sees each one once
+    let sbvh = Bvh::new_with_settings(
+        slanted_tris(),
+        BvhBuildSettings {
+            spatial_splits: true,
+            max_leaf_size: 2,
+            ..default()
+        },
+    );
+    let mut repeated = false;
+    let points = golden_spiral(200);
+    let center = vec3a(25.0, 0.0, 25.0);
+    for (i, point) in points.iter().enumerate() {
+        let origin = center + *point * 150.0;
+        let target = center + points[(i * 17) % points.len()] * 30.0;
+        let ray = RayCast3d::new(origin, Dir3A::new(target - origin).unwrap(), 300.0);
+        let expected = ray
+            .intersect_bvh_all(&sbvh, None)
+            .iter()
+            .map(|hit| hit.tri_index)
+            .collect::<HashSet<_>>();
+
+        let mut seen = HashMap::<usize, usize>::default();
+        let hit = sbvh.intersect_filtered(&ray, &settings, |hit| {
+            *seen.entry(hit.tri_index).or_default() += 1;
+            false
+        });
+        assert!(hit.is_none());
+        assert_eq!(seen.keys().copied().collect::<HashSet<_>>(), expected);
+        repeated |= seen.values().any(|count| *count > 1);
+
+        let mut seen = HashMap::<usize, usize>::default();
+        let hits = sbvh.intersect_all_filtered(&ray, None, &settings, |hit| {
+            *seen.entry(hit.tri_index).or_default() += 1;
+            true
+        });
+        assert_eq!(hits.len(), expected.len());
+        assert_eq!(seen.keys().copied().collect::<HashSet<_>>(), expected);
+        assert!(seen.values().all(|count| *count == 1));
+    }
+    assert!(repeated);
+}
+
+#[test]
+fn tlas_filters() {
+    use bevy::ecs::system::SystemState;
+
+    let mut app = test_app();
+    let handle = app
+        .world_mut()
+        .resource_mut::<Assets<Bvh>>()
+        .add(Bvh::try_from_mesh(&Cuboid::default().mesh().build()).unwrap());
+    // two cubes along the ray, the near one first
+    let near = app
+        .world_mut()
+        .spawn((MeshBvh(handle.clone()), Transform::from_xyz(0.0, 0.0, 2.0)))
+        .id();
+    let far = app
+        .world_mut()
+        .spawn((MeshBvh(handle), Transform::from_xyz(0.0, 0.0, -2.0)))
+        .id();
+    app.update();
+    app.update();
+    let mut state: SystemState<TlasCast> = SystemState::new(app.world_mut());
+    let cast = state.get(app.world());
+    let settings = RayCastSettings::default();
+    let ray = RayCast3d::new(vec3a(0.1, 0.2, 5.0), Dir3A::NEG_Z, 20.0);
+    assert_eq!(cast.intersect_tlas(&ray).unwrap().0, near);
+
+    // the near cube's bvh is never visited
+    let skip_near = |e: Entity| e != near;
+    let (e, hit) = cast
+        .intersect_tlas_filtered(&ray, &settings, skip_near, |e, _| {
+            assert_ne!(e, near);
+            true
+        })
+        .unwrap();
+    assert_eq!(e, far);
+    assert!((hit.distance - 6.5).abs() < 1e-5);
+    let (e, _) = cast
+        .any_hit_tlas_filtered(&ray, &settings, skip_near, |e, _| {
+            assert_ne!(e, near);
+            true
+        })
+        .unwrap();
+    assert_eq!(e, far);
+    let all = cast.intersect_tlas_all_filtered(&ray, None, &settings, skip_near, |e, _| {
+        assert_ne!(e, near);
+        true
+    });
+    assert_eq!(all.len(), 2);
+    assert!(all.iter().all(|(e, _)| *e == far));
+
+    // rejecting hits goes on to the next nearest, the far face of the near cube
+    let (e, hit) = cast
+        .intersect_tlas_filtered(&ray, &settings, |_| true, |_, hit| !hit.front_face)
+        .unwrap();
+    assert_eq!(e, near);
+    assert!((hit.distance - 3.5).abs() < 1e-5);
+    assert!(
+        cast.intersect_tlas_filtered(&ray, &settings, |_| true, |_, _| false)
+            .is_none()
+    );
+    assert!(
+        cast.any_hit_tlas_filtered(&ray, &settings, |_| false, |_, _| true)
+            .is_none()
+    );
+}
+
 fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -105,3 +2110,75 @@ fn setup(
         ));
     }
 }
+
+/// `count` points spread evenly over the unit sphere along a golden spiral
+fn golden_spiral(count: usize) -> Vec<Vec3A> {
+    let golden = std::f32::consts::PI * (3.0 - 5f32.sqrt());
+    (0..count)
+        .map(|i| {
+            let y = 1.0 - 2.0 * (i as f32 + 0.5) / count as f32;
+            let radius = (1.0 - y * y).sqrt();
+            let theta = golden * i as f32;
+            Vec3A::new(radius * theta.cos(), y, radius * theta.sin())
+        })
+        .collect()
+}
+
+/// Slanted triangles overlapping along x, so spatial splits reference them from several leaves
+fn slanted_tris() -> Vec<Tri> {
+    (0..100)
+        .map(|i| {
+            let x = i as f32 * 0.5;
+            Tri::new(
+                vec3a(x, -50.0, -50.0),
+                vec3a(x + 10.0, 50.0, -50.0),
+                vec3a(x + 5.0, 0.0, 100.0),
+            )
+        })
+        .collect()
+}
+
+/// App with the plugins the [`Bvh`] and [`Tlas`] systems need, without rendering
+fn test_app() -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        TransformPlugin,
+        AssetPlugin::default(),
+        ImagePlugin::default(),
+        MeshPlugin,
+        BvhPlugin,
+    ));
+    app
+}
+
+/// A [`Bvh`] over the triangles of `bvh` moved to world space, to compare instance queries with
+fn world_bvh(bvh: &Bvh, transform: Transform) -> Bvh {
+    let affine = GlobalTransform::from(transform).affine();
+    Bvh::new(
+        bvh.tris
+            .iter()
+            .map(|tri| {
+                Tri::new(
+                    affine.transform_point3a(tri.vertex0),
+                    affine.transform_point3a(tri.vertex1),
+                    affine.transform_point3a(tri.vertex2),
+                )
+            })
+            .collect(),
+    )
+}
+
+/// A mesh with only positions and, when given, indices
+fn test_mesh(
+    topology: PrimitiveTopology,
+    positions: Vec<[f32; 3]>,
+    indices: Option<Vec<u32>>,
+) -> Mesh {
+    let mesh = Mesh::new(topology, RenderAssetUsages::default())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    match indices {
+        Some(indices) => mesh.with_inserted_indices(Indices::U32(indices)),
+        None => mesh,
+    }
+}
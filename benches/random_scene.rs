@@ -3,10 +3,7 @@ extern crate test;
 
 // use std::f32::consts::PI;
 
-use std::{
-    f32::consts::PI,
-    hash::{DefaultHasher, Hash},
-};
+use std::f32::consts::PI;
 
 use bevy::{prelude::*, render::mesh::MeshPlugin};
 use image::{ImageBuffer, Rgb};
@@ -31,14 +28,12 @@ fn scene_1k_1024() {
     println!("Camera image saved to: {}", file_path);
 
     // Check against a reference image
-    let mut hasher = DefaultHasher::new();
     let ref_image =
         image::load_from_memory(include_bytes!("../assets/tests/bevy_1k_1024x1024.png"))
             .unwrap()
             .into_rgb8();
-    assert_eq!(
-        image.hash(&mut hasher),
-        ref_image.hash(&mut hasher),
+    assert!(
+        image == ref_image,
         "Rendered image does not match reference image"
     );
 }
@@ -54,14 +49,12 @@ fn scene_100k_1024() {
     println!("Camera image saved to: {}", file_path);
 
     // Check against a reference image
-    let mut hasher = DefaultHasher::new();
     let ref_image =
         image::load_from_memory(include_bytes!("../assets/tests/bevy_100k_1024x1024.png"))
             .unwrap()
             .into_rgb8();
-    assert_eq!(
-        image.hash(&mut hasher),
-        ref_image.hash(&mut hasher),
+    assert!(
+        image == ref_image,
         "Rendered image does not match reference image"
     );
 }
@@ -99,6 +92,22 @@ pub fn random_scene_100k_256(b: &mut Bencher) {
     });
 }
 
+#[bench]
+pub fn random_scene_100k_256_wide(b: &mut Bencher) {
+    b.iter(|| {
+        let (mut app, camera_id) = setup_app::<100, 1000, 256>();
+        app.insert_resource(SceneBuildSettings(BvhBuildSettings {
+            wide: true,
+            ..default()
+        }));
+
+        app.update();
+
+        let image = get_image(app, camera_id);
+        black_box(image);
+    });
+}
+
 fn setup_app<const GROUP_COUNT: usize, const TRI_PER_GROUP: usize, const RESOLUTION: u32>()
 -> (App, Entity) {
     let mut app = App::new();
@@ -111,6 +120,7 @@ fn setup_app<const GROUP_COUNT: usize, const TRI_PER_GROUP: usize, const RESOLUT
         //AssetPlugin::default(),
         BvhPlugin,
     ))
+    .init_resource::<SceneBuildSettings>()
     .add_systems(
         Startup,
         build_random_tri_scene::<GROUP_COUNT, TRI_PER_GROUP>,
@@ -151,10 +161,15 @@ fn get_image(app: App, camera_id: Entity) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
         .to_rgb8()
 }
 
+/// Settings the random scene's Bvhs are built with
+#[derive(Resource, Default)]
+struct SceneBuildSettings(BvhBuildSettings);
+
 /// This is odd but its from non-`bevy` code, kept here so I could benchmark vs old code
 fn build_random_tri_scene<const GROUP_COUNT: usize, const TRI_PER_GROUP: usize>(
     mut commands: Commands,
     mut bvhs: ResMut<Assets<Bvh>>,
+    settings: Res<SceneBuildSettings>,
 ) {
     fn random_vec3(rng: &mut impl Rng) -> Vec3A {
         vec3a(
@@ -193,7 +208,7 @@ fn build_random_tri_scene<const GROUP_COUNT: usize, const TRI_PER_GROUP: usize>(
                     0.0,
                     j as f32 * offset - side_offset + (offset * 0.5),
                 ),
-                MeshBvh(bvhs.add(Bvh::new(tris))),
+                MeshBvh(bvhs.add(Bvh::new_with_settings(tris, settings.0.clone()))),
             ));
         }
     }
@@ -112,9 +112,9 @@ fn ray_cast(
 
         // Convert the ray to local space of the mesh
         let (local_ray, dir_scale) = ray.to_local(transform);
-        if let Some(mut hit) = local_ray.intersect_bvh(&bvh) {
-            // Scale the distance back to world space
-            hit.distance /= dir_scale;
+        if let Some(hit) = local_ray.intersect_bvh(&bvh) {
+            // Convert the hit back to world space
+            let hit = hit.to_world(transform, dir_scale);
             // Test vs best hit so far
             if let Some(best) = best_hit {
                 if hit.distance < best.distance {
@@ -128,10 +128,8 @@ fn ray_cast(
 
     // draw line of the ray
     if let Some(hit) = best_hit {
-        gizmos.sphere(
-            Vec3::from(ray.get_point(hit.distance)),
-            0.2,
-            tailwind::GREEN_500,
-        );
+        let point = Vec3::from(hit.point);
+        gizmos.sphere(point, 0.2, tailwind::GREEN_500);
+        gizmos.arrow(point, point + Vec3::from(hit.normal), tailwind::GREEN_500);
     }
 }
@@ -0,0 +1,282 @@
+use bevy::{
+    asset::{
+        AssetLoader, LoadContext, LoadDirectError,
+        io::{Reader, Writer},
+        processor::LoadTransformAndSave,
+        saver::{AssetSaver, SavedAsset},
+        transformer::IdentityAssetTransformer,
+    },
+    gltf::{Gltf, GltfLoaderSettings},
+    platform::collections::HashMap,
+    prelude::*,
+    render::render_asset::RenderAssetUsages,
+    tasks::futures_lite::AsyncWriteExt,
+};
+
+use crate::{
+    bvh::{Bvh, BvhBuildSettings},
+    format::{BVH_FORMAT_VERSION, BvhFormatError},
+};
+
+/// First bytes of every baked `.bvhs` file
+pub const BAKED_BVHS_MAGIC: [u8; 4] = *b"RBVS";
+
+/// Prebuilt [`Bvh`]s for every mesh of a glTF, keyed by the mesh's label in the glTF
+/// (`Mesh0/Primitive0`), each [`Bvh`] is a labeled sub asset under the same label.
+///
+/// Load `<path to gltf>.bvhs`, an empty file placed next to the glTF, see
+/// [`GltfBvhBakeLoader`]. With the asset processor the [`GltfBvhProcessor`] bakes it ahead of
+/// time, without it the Bvhs are built on load.
+#[derive(Asset, TypePath, Debug, Default)]
+pub struct BakedBvhs {
+    pub bvhs: HashMap<String, Handle<Bvh>>,
+}
+
+/// Asset processor baking the [`BakedBvhs`] of a glTF, the default processor for `.bvhs`
+pub type GltfBvhProcessor =
+    LoadTransformAndSave<GltfBvhBakeLoader, IdentityAssetTransformer<BakedBvhs>, BakedBvhsSaver>;
+
+/// Builds the [`BakedBvhs`] for the glTF next to a `.bvhs` file, `scene.gltf.bvhs` bakes
+/// `scene.gltf`.
+///
+/// The asset processor only processes files that exist, so the `.bvhs` file has to be created
+/// by hand next to the glTF, it can be empty since its contents are never read. The Bvhs are
+/// built with the loader's [`BvhBuildSettings`], set in the `.bvhs.meta` file or with
+/// [`AssetServer::load_with_settings`].
+#[derive(Default)]
+pub struct GltfBvhBakeLoader;
+
+impl AssetLoader for GltfBvhBakeLoader {
+    type Asset = BakedBvhs;
+    type Settings = BvhBuildSettings;
+    type Error = BvhBakeError;
+
+    async fn load(
+        &self,
+        _reader: &mut dyn Reader,
+        settings: &BvhBuildSettings,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<BakedBvhs, BvhBakeError> {
+        let gltf_path = load_context.path().with_extension("");
+        let gltf = load_context
+            .loader()
+            .with_settings(|settings: &mut GltfLoaderSettings| {
+                // only the meshes are needed, and only on the cpu
+                settings.load_meshes = RenderAssetUsages::MAIN_WORLD;
+                settings.load_materials = RenderAssetUsages::empty();
+                settings.load_cameras = false;
+                settings.load_lights = false;
+            })
+            .immediate()
+            .load::<Gltf>(gltf_path)
+            .await
+            .map_err(|err| BvhBakeError::Gltf(Box::new(err)))?;
+
+        let mut labels = gltf
+            .iter_labels()
+            .filter(|label| {
+                gltf.get_labeled(label.to_string())
+                    .is_some_and(|asset| asset.get::<Mesh>().is_some())
+            })
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        labels.sort();
+
+        let mut bvhs = HashMap::default();
+        for label in labels {
+            let mesh = gltf
+                .get_labeled(label.clone())
+                .and_then(|asset| asset.get::<Mesh>())
+                .unwrap();
+            match Bvh::try_from_mesh_with_settings(mesh, settings.clone()) {
+                Ok(bvh) => {
+                    let handle = load_context.add_labeled_asset(label.clone(), bvh);
+                    bvhs.insert(label, handle);
+                }
+                Err(err) => warn!("Skipping Bvh for {label}: {err}"),
+            }
+        }
+        Ok(BakedBvhs { bvhs })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["bvhs"]
+    }
+}
+
+/// Reasons [`BakedBvhs`] can't be baked, saved or loaded
+#[derive(Debug)]
+pub enum BvhBakeError {
+    Io(std::io::Error),
+    /// A baked Bvh or the `.bvhs` file around them is corrupt
+    Format(BvhFormatError),
+    /// The glTF next to the `.bvhs` file couldn't be loaded
+    Gltf(Box<LoadDirectError>),
+    /// A label in a baked `.bvhs` file isn't valid utf8
+    InvalidLabel(std::string::FromUtf8Error),
+    /// [`BakedBvhs::bvhs`] has a label without a matching labeled [`Bvh`]
+    MissingBvh(String),
+}
+
+impl std::fmt::Display for BvhBakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BvhBakeError::Io(err) => write!(f, "io error: {err}"),
+            BvhBakeError::Format(err) => write!(f, "{err}"),
+            BvhBakeError::Gltf(err) => write!(f, "failed to load glTF: {err}"),
+            BvhBakeError::InvalidLabel(err) => write!(f, "invalid label: {err}"),
+            BvhBakeError::MissingBvh(label) => write!(f, "missing Bvh for {label}"),
+        }
+    }
+}
+
+impl std::error::Error for BvhBakeError {}
+
+impl From<std::io::Error> for BvhBakeError {
+    fn from(err: std::io::Error) -> Self {
+        BvhBakeError::Io(err)
+    }
+}
+
+impl From<BvhFormatError> for BvhBakeError {
+    fn from(err: BvhFormatError) -> Self {
+        BvhBakeError::Format(err)
+    }
+}
+
+/// Writes [`BakedBvhs`] as a list of labeled `.bvh` blobs, read by [`BakedBvhsLoader`]:
+///
+/// - header: [`BAKED_BVHS_MAGIC`], [`BVH_FORMAT_VERSION`], entry count
+/// - entries: label length and utf8 label, then the length and bytes of [`Bvh::to_bytes`]
+pub struct BakedBvhsSaver;
+
+impl AssetSaver for BakedBvhsSaver {
+    type Asset = BakedBvhs;
+    type Settings = ();
+    type OutputLoader = BakedBvhsLoader;
+    type Error = BvhBakeError;
+
+    async fn save(
+        &self,
+        writer: &mut Writer,
+        asset: SavedAsset<'_, BakedBvhs>,
+        _settings: &(),
+    ) -> Result<(), BvhBakeError> {
+        let mut labels = asset.get().bvhs.keys().collect::<Vec<_>>();
+        labels.sort();
+
+        let bvhs = labels
+            .into_iter()
+            .map(|label| {
+                asset
+                    .get_labeled::<Bvh, _>(label.as_str())
+                    .map(|bvh| (label.as_str(), bvh.get()))
+                    .ok_or_else(|| BvhBakeError::MissingBvh(label.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let bytes = baked_bvhs_to_bytes(bvhs);
+        writer.write_all(&bytes).await?;
+        Ok(())
+    }
+}
+
+/// Loads [`BakedBvhs`] written by [`BakedBvhsSaver`], picked through the processed asset's meta
+/// rather than by extension
+#[derive(Default)]
+pub struct BakedBvhsLoader;
+
+impl AssetLoader for BakedBvhsLoader {
+    type Asset = BakedBvhs;
+    type Settings = ();
+    type Error = BvhBakeError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<BakedBvhs, BvhBakeError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let mut bvhs = HashMap::default();
+        for (label, bvh) in baked_bvhs_from_bytes(&bytes)? {
+            let handle = load_context.add_labeled_asset(label.clone(), bvh);
+            bvhs.insert(label, handle);
+        }
+        Ok(BakedBvhs { bvhs })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[]
+    }
+}
+
+/// Encodes labeled [`Bvh`]s in the `.bvhs` format of [`BakedBvhsSaver`], in the given order
+pub fn baked_bvhs_to_bytes<'a>(bvhs: impl IntoIterator<Item = (&'a str, &'a Bvh)>) -> Vec<u8> {
+    let bvhs = bvhs.into_iter().collect::<Vec<_>>();
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&BAKED_BVHS_MAGIC);
+    bytes.extend_from_slice(&BVH_FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(bvhs.len() as u32).to_le_bytes());
+    for (label, bvh) in bvhs {
+        let bvh_bytes = bvh.to_bytes();
+        bytes.extend_from_slice(&(label.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(label.as_bytes());
+        bytes.extend_from_slice(&(bvh_bytes.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&bvh_bytes);
+    }
+    bytes
+}
+
+/// Decodes the labeled [`Bvh`]s of a `.bvhs` file written by [`baked_bvhs_to_bytes`]
+pub fn baked_bvhs_from_bytes(bytes: &[u8]) -> Result<Vec<(String, Bvh)>, BvhBakeError> {
+    if bytes.len() < 8 || bytes[..4] != BAKED_BVHS_MAGIC {
+        return Err(BvhFormatError::InvalidMagic.into());
+    }
+    let mut pos = 4;
+    let version = read_u32(bytes, &mut pos)?;
+    if version != BVH_FORMAT_VERSION {
+        return Err(BvhFormatError::UnsupportedVersion(version).into());
+    }
+
+    let count = read_u32(bytes, &mut pos)?;
+    let mut bvhs = Vec::new();
+    for _ in 0..count {
+        let label = read_blob(bytes, &mut pos)?;
+        let label = String::from_utf8(label.to_vec()).map_err(BvhBakeError::InvalidLabel)?;
+        let bvh = Bvh::from_bytes(read_blob(bytes, &mut pos)?)?;
+        bvhs.push((label, bvh));
+    }
+    if pos != bytes.len() {
+        return Err(BvhFormatError::SizeMismatch {
+            expected: pos,
+            found: bytes.len(),
+        }
+        .into());
+    }
+    Ok(bvhs)
+}
+
+fn read_u32(bytes: &[u8], pos: &mut usize) -> Result<u32, BvhFormatError> {
+    let value = bytes
+        .get(*pos..*pos + 4)
+        .ok_or(BvhFormatError::SizeMismatch {
+            expected: *pos + 4,
+            found: bytes.len(),
+        })?;
+    *pos += 4;
+    Ok(u32::from_le_bytes(value.try_into().unwrap()))
+}
+
+/// Length prefixed bytes
+fn read_blob<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a [u8], BvhFormatError> {
+    let len = read_u32(bytes, pos)? as usize;
+    // a corrupt length can't overflow, it's past the end of any file either way
+    let end = pos.checked_add(len).unwrap_or(usize::MAX);
+    let blob = bytes.get(*pos..end).ok_or(BvhFormatError::SizeMismatch {
+        expected: end,
+        found: bytes.len(),
+    })?;
+    *pos = end;
+    Ok(blob)
+}
@@ -1,14 +1,19 @@
-use crate::{BIN_COUNT, aabb::Aabb3dExt};
-use bevy::{math::bounding::Aabb3d, prelude::*, render::mesh::*};
+use core::time::Duration;
 
-/// Note: we really want this to be 32 bytes, so things layout in on nice 64 bytes pages in memory, using Vec3A instead of Vec3 in
-/// aabb, puts us at 48, instead of 32
-// pub struct Aabb {
-//     pub min: Vec3,
-//     pub max: Vec3,
-// }
+use crate::{aabb::Aabb3dExt, wide::WideBvh};
+use bevy::{
+    math::bounding::{Aabb3d, BoundingVolume},
+    platform::time::Instant,
+    prelude::*,
+    render::mesh::*,
+    tasks::{ComputeTaskPool, Task},
+};
+use serde::{Deserialize, Serialize};
 
 /// A BVH node, which is a node in the bounding volume hierarchy (BVH).
+///
+/// Using Vec3A in the aabb puts this at 48 bytes, [`Bvh`] stores [`CompactBvhNode`]s instead so
+/// nodes layout on nice 64 byte cache lines, this is the layout used while building.
 #[derive(Debug, Clone, Copy)]
 pub struct BvhNode {
     pub aabb: Aabb3d,
@@ -47,58 +52,584 @@ impl BvhNode {
     }
 }
 
+impl From<CompactBvhNode> for BvhNode {
+    fn from(node: CompactBvhNode) -> Self {
+        BvhNode {
+            aabb: node.aabb(),
+            left_first: node.left_first,
+            tri_count: node.tri_count,
+        }
+    }
+}
+
+/// The 32 byte node [`Bvh`] stores, so siblings share a single 64 byte cache line.
+///
+/// Same meaning as [`BvhNode`], which it converts to and from, with the bounds stored as `Vec3`
+#[repr(C, align(32))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactBvhNode {
+    pub min: Vec3,
+    pub left_first: u32,
+    pub max: Vec3,
+    pub tri_count: u32,
+}
+
+impl Default for CompactBvhNode {
+    fn default() -> Self {
+        BvhNode::default().into()
+    }
+}
+
+impl From<BvhNode> for CompactBvhNode {
+    fn from(node: BvhNode) -> Self {
+        CompactBvhNode {
+            min: node.aabb.min.into(),
+            left_first: node.left_first,
+            max: node.aabb.max.into(),
+            tri_count: node.tri_count,
+        }
+    }
+}
+
+impl CompactBvhNode {
+    #[inline]
+    pub fn aabb(&self) -> Aabb3d {
+        Aabb3d {
+            min: self.min.into(),
+            max: self.max.into(),
+        }
+    }
+
+    #[inline]
+    pub fn set_aabb(&mut self, aabb: Aabb3d) {
+        self.min = aabb.min.into();
+        self.max = aabb.max.into();
+    }
+
+    #[inline]
+    pub fn is_leaf(&self) -> bool {
+        self.tri_count > 0
+    }
+
+    #[inline]
+    pub fn calculate_cost(&self) -> f32 {
+        self.tri_count as f32 * self.aabb().area()
+    }
+}
+
+/// A cache line holding a pair of sibling nodes
+#[repr(C, align(64))]
+#[derive(Debug, Default, Clone, Copy)]
+struct NodeLine([CompactBvhNode; 2]);
+
+/// 64 byte aligned node storage for a [`Bvh`], indexed like a `Vec<CompactBvhNode>`.
+///
+/// The root sits alone in the first line, followed by an unused slot, so the sibling pairs the
+/// builders push after it (at odd indexes) each fill exactly one cache line. Use
+/// [`BvhNodes::to_vec`] or [`BvhNode::from`] to read nodes in the older 48 byte layout.
+#[derive(Debug, Default, Clone)]
+pub struct BvhNodes {
+    lines: Vec<NodeLine>,
+    len: usize,
+}
+
+impl BvhNodes {
+    pub fn with_capacity(capacity: usize) -> Self {
+        BvhNodes {
+            lines: Vec::with_capacity(capacity.div_ceil(2) + 1),
+            len: 0,
+        }
+    }
+
+    /// Line and position in the line of a node, skipping the slot after the root
+    #[inline]
+    fn slot(index: usize) -> (usize, usize) {
+        let slot = if index == 0 { 0 } else { index + 1 };
+        (slot / 2, slot % 2)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, node: impl Into<CompactBvhNode>) {
+        let (line, i) = Self::slot(self.len);
+        if line == self.lines.len() {
+            self.lines.push(NodeLine::default());
+        }
+        self.lines[line].0[i] = node.into();
+        self.len += 1;
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) {
+        let node_a = self[a];
+        self[a] = self[b];
+        self[b] = node_a;
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.len = 0;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &CompactBvhNode> {
+        (0..self.len).map(|i| &self[i])
+    }
+
+    /// Copies the nodes out in the [`BvhNode`] layout
+    pub fn to_vec(&self) -> Vec<BvhNode> {
+        self.iter().map(|node| BvhNode::from(*node)).collect()
+    }
+
+    /// Bytes allocated for the nodes, including the unused slot and spare capacity
+    pub fn allocated_bytes(&self) -> usize {
+        self.lines.capacity() * size_of::<NodeLine>()
+    }
+}
+
+impl core::ops::Index<usize> for BvhNodes {
+    type Output = CompactBvhNode;
+
+    #[inline]
+    fn index(&self, index: usize) -> &CompactBvhNode {
+        assert!(index < self.len, "node index {index} out of bounds");
+        let (line, i) = Self::slot(index);
+        &self.lines[line].0[i]
+    }
+}
+
+impl core::ops::IndexMut<usize> for BvhNodes {
+    #[inline]
+    fn index_mut(&mut self, index: usize) -> &mut CompactBvhNode {
+        assert!(index < self.len, "node index {index} out of bounds");
+        let (line, i) = Self::slot(index);
+        &mut self.lines[line].0[i]
+    }
+}
+
+impl<N: Into<CompactBvhNode>> FromIterator<N> for BvhNodes {
+    fn from_iter<T: IntoIterator<Item = N>>(iter: T) -> Self {
+        let iter = iter.into_iter();
+        let mut nodes = BvhNodes::with_capacity(iter.size_hint().0);
+        for node in iter {
+            nodes.push(node);
+        }
+        nodes
+    }
+}
+
 /// A handle to a BVH asset
 #[derive(Component, Default, Clone, Debug, Deref, DerefMut, Reflect)]
 #[reflect(Component)]
 pub struct MeshBvh(pub Handle<Bvh>);
 
+/// The mesh a [`MeshBvh`] was built from, used to keep the bvh in sync when the mesh changes.
+/// Also kept next to a [`MeshBvhError`](crate::MeshBvhError), to retry once the mesh is fixed
+#[derive(Component, Default, Clone, Debug, Deref, DerefMut, Reflect)]
+#[reflect(Component)]
+pub struct MeshBvhSource(pub Handle<Mesh>);
+
+/// Added by the spawn helpers while a [`Bvh`] builds in the background, replaced by [`MeshBvh`]
+/// once it's done. Rebuilds keep using the current [`MeshBvh`] until then, entities without one
+/// are left out of the Tlas.
+#[derive(Component, Debug)]
+pub struct PendingMeshBvh {
+    /// The background build
+    pub task: Task<Bvh>,
+    /// Mesh the bvh is built from, becomes the [`MeshBvhSource`]
+    pub source: Handle<Mesh>,
+    /// Settings the bvh is built with, reused if the build has to start over
+    pub settings: BvhBuildSettings,
+}
+
+/// Controls how a [`Bvh`] is built, trading build time for trace speed.
+///
+/// Can be added as a component next to [`SpawnMeshBvh`](crate::SpawnMeshBvh) or
+/// [`SpawnSceneBvhs`](crate::SpawnSceneBvhs) to change how the helpers build, on a scene
+/// root it applies to every mesh in the scene without its own settings. Also the loader
+/// settings of [`GltfBvhBakeLoader`](crate::bake::GltfBvhBakeLoader) when baking.
+#[derive(Component, Debug, Clone, PartialEq, Reflect, Serialize, Deserialize)]
+#[reflect(Component)]
+#[serde(default)]
+pub struct BvhBuildSettings {
+    /// Number of bins used to find the best SAH split plane on each axis, more bins
+    /// give better splits at the cost of build time
+    pub bin_count: usize,
+    /// Cost of visiting a node, relative to `intersection_cost`
+    pub traversal_cost: f32,
+    /// Cost of intersecting a single triangle
+    pub intersection_cost: f32,
+    /// Nodes with this many triangles or less are never split
+    pub min_leaf_size: u32,
+    /// Nodes with more triangles than this are split even when SAH says not to
+    pub max_leaf_size: u32,
+    /// Nodes at this depth are never split, takes priority over `max_leaf_size`
+    pub max_depth: u32,
+    /// Allow spatial splits (SBVH), clipping triangles that straddle a split plane into both
+    /// children, slower to build but much tighter nodes for long thin or overlapping triangles
+    pub spatial_splits: bool,
+    /// Only try spatial splits when object split children overlap by more than this fraction
+    /// of the root surface area, lower values try spatial splits more often
+    pub spatial_split_alpha: f32,
+    /// Build a linear BVH (LBVH) by sorting triangle centroids along a Morton curve, much
+    /// faster than binned SAH but with looser nodes, for geometry rebuilt every few frames.
+    /// Takes priority over `spatial_splits`, only the leaf size and depth limits apply
+    pub linear: bool,
+    /// Also collapse the tree into a [`WideBvh`], ray casts then test 4 child boxes at a time.
+    /// The binary tree is built the same either way
+    pub wide: bool,
+    /// Passes of [`Bvh::optimize`] to run after building, worth it for static geometry
+    pub optimize_passes: u32,
+    /// Build large subtrees in parallel on the [`ComputeTaskPool`] when it's available, the
+    /// resulting tree is identical to a serial build
+    pub parallel: bool,
+}
+
+impl Default for BvhBuildSettings {
+    fn default() -> Self {
+        Self {
+            bin_count: 8,
+            traversal_cost: 0.0,
+            intersection_cost: 1.0,
+            min_leaf_size: 1,
+            max_leaf_size: u32::MAX,
+            max_depth: 64,
+            spatial_splits: false,
+            spatial_split_alpha: 1e-5,
+            linear: false,
+            wide: false,
+            optimize_passes: 0,
+            parallel: true,
+        }
+    }
+}
+
+impl BvhBuildSettings {
+    /// Clamps the settings to values the builders can work with, at least 2 bins, leaves of at
+    /// least 1 triangle and `min_leaf_size` no bigger than `max_leaf_size`
+    pub fn clamped(&self) -> Self {
+        let max_leaf_size = self.max_leaf_size.max(1);
+        Self {
+            bin_count: self.bin_count.max(2),
+            min_leaf_size: self.min_leaf_size.min(max_leaf_size),
+            max_leaf_size,
+            ..self.clone()
+        }
+    }
+}
+
 /// Bounded Volume Hierarchy (BVH) spatial data structure used for efficient ray casting
 #[derive(Asset, Default, TypePath, Debug)]
 pub struct Bvh {
-    pub nodes: Vec<BvhNode>,
+    pub nodes: BvhNodes,
     pub tris: Vec<Tri>,
+    /// Indexes into `tris` for each leaf, spatial splits can reference a triangle more than once
     pub triangle_indexs: Vec<usize>,
+    /// SAH cost at build time, used to judge refit degradation
+    pub build_cost: f32,
+    /// Settings the bvh was built with after [`BvhBuildSettings::clamped`], reused when rebuilding
+    pub settings: BvhBuildSettings,
+    /// How long the build took, including any optimize passes
+    pub build_time: Duration,
+    /// Wide version of `nodes`, used for ray casts when present
+    pub wide: Option<WideBvh>,
+    /// Where each triangle in `tris` came from, in the same order, empty when not built from a
+    /// [`Mesh`] and no user ids were set, returned with hits as
+    /// [`Hit::source`](crate::util::Hit::source)
+    pub tri_sources: Vec<TriSource>,
+}
+
+/// Links a triangle of a [`Bvh`] back to the mesh it was built from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TriSource {
+    /// Indexes of the triangle's vertices in the mesh's vertex buffers
+    pub vertices: [u32; 3],
+    /// Index of the mesh when built with [`Bvh::try_from_sub_meshes`], 0 otherwise
+    pub sub_mesh: u32,
+    /// Id for game logic, such as a material or surface type, 0 unless set
+    pub user_id: u32,
+}
+
+/// A mesh to merge into a [`Bvh`] with [`Bvh::try_from_sub_meshes`], such as a primitive of a
+/// glTF mesh
+#[derive(Debug, Clone, Copy)]
+pub struct BvhSubMesh<'a> {
+    pub mesh: &'a Mesh,
+    /// Given to every triangle of the mesh, see [`TriSource::user_id`]
+    pub user_id: u32,
+}
+
+/// Reasons a [`Bvh`] can't be built from a [`Mesh`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum BvhBuildError {
+    /// Only triangle topologies can be ray cast against
+    UnsupportedTopology(PrimitiveTopology),
+    MissingPositions,
+    UnsupportedPositionFormat(VertexFormat),
+    /// An index refers to a vertex that doesn't exist
+    IndexOutOfBounds {
+        index: usize,
+        vertex_count: usize,
+    },
+    /// The mesh has no triangles to build from
+    NoTriangles,
+    /// Refitting requires the same triangle count the [`Bvh`] was built with
+    TriangleCountMismatch {
+        expected: usize,
+        found: usize,
+    },
 }
 
+impl std::fmt::Display for BvhBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BvhBuildError::UnsupportedTopology(topology) => {
+                write!(f, "unsupported primitive topology {topology:?}")
+            }
+            BvhBuildError::MissingPositions => write!(f, "mesh has no position attribute"),
+            BvhBuildError::UnsupportedPositionFormat(format) => {
+                write!(f, "unsupported position format {format:?}")
+            }
+            BvhBuildError::IndexOutOfBounds {
+                index,
+                vertex_count,
+            } => write!(
+                f,
+                "index {index} out of bounds for mesh with {vertex_count} vertices"
+            ),
+            BvhBuildError::NoTriangles => write!(f, "mesh has no triangles"),
+            BvhBuildError::TriangleCountMismatch { expected, found } => {
+                write!(f, "expected {expected} triangles to refit, found {found}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BvhBuildError {}
+
+/// Panics if the mesh can't be converted, see [`Bvh::try_from_mesh`] for a fallible version
 impl From<&Mesh> for Bvh {
     fn from(mesh: &Mesh) -> Self {
-        let triangles = match mesh.primitive_topology() {
-            PrimitiveTopology::TriangleList => {
-                let indexes = match mesh.indices().expect("Mesh should have Indices") {
-                    Indices::U32(vec) => &vec.iter().map(|i| *i as usize).collect::<Vec<_>>(),
-                    Indices::U16(vec) => &vec.iter().map(|i| *i as usize).collect::<Vec<_>>(),
-                };
-                let verts = match mesh
-                    .attribute(Mesh::ATTRIBUTE_POSITION)
-                    .expect("Mesh should have Position Attribute")
-                {
-                    VertexAttributeValues::Float32x3(vec) => {
-                        vec.iter().map(|vec| vec3a(vec[0], vec[1], vec[2]))
-                    }
-                    _ => todo!(),
-                }
-                .collect::<Vec<_>>();
-
-                let mut triangles = Vec::with_capacity(indexes.len() / 3);
-                for tri_indexes in indexes.chunks(3) {
-                    triangles.push(Tri::new(
-                        verts[tri_indexes[0]],
-                        verts[tri_indexes[1]],
-                        verts[tri_indexes[2]],
-                    ));
+        Self::try_from_mesh(mesh).expect("Failed to build Bvh from Mesh")
+    }
+}
+
+/// Marks a primitive restart in strip indices
+const STRIP_RESTART: usize = usize::MAX;
+
+/// Triangles of a mesh and where each came from
+pub(crate) type MeshTriangles = (Vec<Tri>, Vec<TriSource>);
+
+/// Collects the triangles of a mesh in index order, non-indexed meshes use vertex order.
+/// Meshes without any triangles are an error, there would be nothing to ray cast against.
+pub(crate) fn mesh_triangles(mesh: &Mesh) -> Result<MeshTriangles, BvhBuildError> {
+    let topology = mesh.primitive_topology();
+    if !matches!(
+        topology,
+        PrimitiveTopology::TriangleList | PrimitiveTopology::TriangleStrip
+    ) {
+        return Err(BvhBuildError::UnsupportedTopology(topology));
+    }
+
+    let verts = mesh_positions(
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+            .ok_or(BvhBuildError::MissingPositions)?,
+    )?;
+
+    // restarts are only valid in strips, in lists they keep their value and are out of bounds
+    let is_strip = topology == PrimitiveTopology::TriangleStrip;
+    let indexes = match mesh.indices() {
+        Some(Indices::U32(vec)) => vec
+            .iter()
+            .map(|i| match *i {
+                u32::MAX if is_strip => STRIP_RESTART,
+                i => i as usize,
+            })
+            .collect::<Vec<_>>(),
+        Some(Indices::U16(vec)) => vec
+            .iter()
+            .map(|i| match *i {
+                u16::MAX if is_strip => STRIP_RESTART,
+                i => i as usize,
+            })
+            .collect::<Vec<_>>(),
+        None => (0..verts.len()).collect::<Vec<_>>(),
+    };
+
+    if let Some(index) = indexes
+        .iter()
+        .find(|i| **i >= verts.len() && !(is_strip && **i == STRIP_RESTART))
+    {
+        return Err(BvhBuildError::IndexOutOfBounds {
+            index: *index,
+            vertex_count: verts.len(),
+        });
+    }
+
+    let mut triangles = Vec::with_capacity(indexes.len() / 3);
+    let mut sources = Vec::with_capacity(indexes.len() / 3);
+    if is_strip {
+        for strip in indexes.split(|i| *i == STRIP_RESTART) {
+            for (i, window) in strip.windows(3).enumerate() {
+                // degenerate triangles are used to stitch strips together, skip them
+                if window[0] == window[1] || window[1] == window[2] || window[0] == window[2] {
+                    continue;
                 }
-                triangles
+                // every other triangle in a strip is flipped, swap to keep the winding consistent
+                let (a, b) = if i % 2 == 0 {
+                    (window[0], window[1])
+                } else {
+                    (window[1], window[0])
+                };
+                triangles.push(Tri::new(verts[a], verts[b], verts[window[2]]));
+                sources.push(TriSource {
+                    vertices: [a as u32, b as u32, window[2] as u32],
+                    ..default()
+                });
             }
-            _ => unimplemented!(),
+        }
+    } else {
+        for tri_indexes in indexes.chunks_exact(3) {
+            triangles.push(Tri::new(
+                verts[tri_indexes[0]],
+                verts[tri_indexes[1]],
+                verts[tri_indexes[2]],
+            ));
+            sources.push(TriSource {
+                vertices: [
+                    tri_indexes[0] as u32,
+                    tri_indexes[1] as u32,
+                    tri_indexes[2] as u32,
+                ],
+                ..default()
+            });
+        }
+    }
+    if triangles.is_empty() {
+        return Err(BvhBuildError::NoTriangles);
+    }
+    Ok((triangles, sources))
+}
+
+/// Converts any position encoding Bevy can render to [`Vec3A`], normalized formats are
+/// mapped to their float range the same way the gpu would
+fn mesh_positions(values: &VertexAttributeValues) -> Result<Vec<Vec3A>, BvhBuildError> {
+    macro_rules! xyz {
+        ($vec:expr, $f:expr) => {
+            $vec.iter()
+                .map(|p| vec3a($f(p[0]), $f(p[1]), $f(p[2])))
+                .collect()
         };
-        Self::new(triangles)
     }
+
+    let verts = match values {
+        VertexAttributeValues::Float32x2(vec) => {
+            vec.iter().map(|p| vec3a(p[0], p[1], 0.0)).collect()
+        }
+        VertexAttributeValues::Float32x3(vec) => xyz!(vec, |x: f32| x),
+        VertexAttributeValues::Float32x4(vec) => xyz!(vec, |x: f32| x),
+        VertexAttributeValues::Sint32x3(vec) => xyz!(vec, |x: i32| x as f32),
+        VertexAttributeValues::Sint32x4(vec) => xyz!(vec, |x: i32| x as f32),
+        VertexAttributeValues::Uint32x3(vec) => xyz!(vec, |x: u32| x as f32),
+        VertexAttributeValues::Uint32x4(vec) => xyz!(vec, |x: u32| x as f32),
+        VertexAttributeValues::Sint16x4(vec) => xyz!(vec, |x: i16| x as f32),
+        VertexAttributeValues::Uint16x4(vec) => xyz!(vec, |x: u16| x as f32),
+        VertexAttributeValues::Snorm16x4(vec) => {
+            xyz!(vec, |x: i16| (x as f32 / i16::MAX as f32).max(-1.0))
+        }
+        VertexAttributeValues::Unorm16x4(vec) => xyz!(vec, |x: u16| x as f32 / u16::MAX as f32),
+        VertexAttributeValues::Sint8x4(vec) => xyz!(vec, |x: i8| x as f32),
+        VertexAttributeValues::Uint8x4(vec) => xyz!(vec, |x: u8| x as f32),
+        VertexAttributeValues::Snorm8x4(vec) => {
+            xyz!(vec, |x: i8| (x as f32 / i8::MAX as f32).max(-1.0))
+        }
+        VertexAttributeValues::Unorm8x4(vec) => xyz!(vec, |x: u8| x as f32 / u8::MAX as f32),
+        values => return Err(BvhBuildError::UnsupportedPositionFormat(values.into())),
+    };
+    Ok(verts)
 }
 
 impl Bvh {
+    /// Builds a Bvh from the triangles of a mesh, returning an error for meshes that
+    /// can't be ray cast against instead of panicking
+    pub fn try_from_mesh(mesh: &Mesh) -> Result<Bvh, BvhBuildError> {
+        Self::try_from_mesh_with_settings(mesh, BvhBuildSettings::default())
+    }
+
+    /// Same as [`Bvh::try_from_mesh`], built with the given settings
+    pub fn try_from_mesh_with_settings(
+        mesh: &Mesh,
+        settings: BvhBuildSettings,
+    ) -> Result<Bvh, BvhBuildError> {
+        Ok(Self::from_mesh_triangles(mesh_triangles(mesh)?, settings))
+    }
+
+    /// Builds a single Bvh from several meshes sharing a transform, like the primitives of a
+    /// glTF mesh, hits report which mesh was hit with [`TriSource::sub_mesh`]
+    pub fn try_from_sub_meshes(
+        sub_meshes: &[BvhSubMesh],
+        settings: BvhBuildSettings,
+    ) -> Result<Bvh, BvhBuildError> {
+        let mut triangles = Vec::new();
+        let mut sources = Vec::new();
+        for (i, sub_mesh) in sub_meshes.iter().enumerate() {
+            let (tris, tri_sources) = mesh_triangles(sub_mesh.mesh)?;
+            triangles.extend(tris);
+            sources.extend(tri_sources.into_iter().map(|source| TriSource {
+                sub_mesh: i as u32,
+                user_id: sub_mesh.user_id,
+                ..source
+            }));
+        }
+        Ok(Self::from_mesh_triangles((triangles, sources), settings))
+    }
+
+    /// Builds from the output of [`mesh_triangles`], keeping where each triangle came from
+    pub(crate) fn from_mesh_triangles(
+        (triangles, sources): MeshTriangles,
+        settings: BvhBuildSettings,
+    ) -> Bvh {
+        let mut bvh = Self::new_with_settings(triangles, settings);
+        bvh.tri_sources = sources;
+        bvh
+    }
+
+    /// Sets the [`TriSource::user_id`] of each triangle in `tris` order, triangles past the end
+    /// of `ids` keep theirs
+    pub fn set_user_ids(&mut self, ids: impl IntoIterator<Item = u32>) {
+        self.tri_sources
+            .resize(self.tris.len(), TriSource::default());
+        for (source, id) in self.tri_sources.iter_mut().zip(ids) {
+            source.user_id = id;
+        }
+    }
+
+    /// Replaces the sources after the mesh changed, keeping the user ids when the triangle count
+    /// is the same
+    #[cfg(feature = "helpers")]
+    pub(crate) fn update_tri_sources(&mut self, mut sources: Vec<TriSource>) {
+        if sources.len() == self.tri_sources.len() {
+            for (source, old) in sources.iter_mut().zip(&self.tri_sources) {
+                source.user_id = old.user_id;
+            }
+        }
+        self.tri_sources = sources;
+    }
+
     pub fn new(triangles: Vec<Tri>) -> Bvh {
+        Self::new_with_settings(triangles, BvhBuildSettings::default())
+    }
+
+    pub fn new_with_settings(triangles: Vec<Tri>, settings: BvhBuildSettings) -> Bvh {
+        let start = Instant::now();
         let count = triangles.len() as u32;
-        let mut nodes = Vec::with_capacity(64);
+        let mut nodes = BvhNodes::with_capacity(64);
 
         // reserve a root node
         nodes.push(BvhNode {
@@ -107,173 +638,526 @@ impl Bvh {
             aabb: Aabb3d::init(),
         });
 
-        // Note: Due to no longer being 32 bytes, we can no longer add dummy nodes to align the tree to 64 bytes
-        // nodes.push(BvhNode {
-        //     left_first: 0,
-        //     tri_count: 0,
-        //     aabb: Aabb3d::init(),
-        // });
-
         let mut bvh = Bvh {
             tris: triangles,
             nodes,
             triangle_indexs: (0..count as usize).collect::<Vec<_>>(),
+            build_cost: 0.0,
+            settings: settings.clamped(),
+            build_time: Duration::ZERO,
+            wide: None,
+            tri_sources: Vec::new(),
         };
 
+        // nothing to build, an empty bvh never hits
+        if count == 0 {
+            bvh.build_time = start.elapsed();
+            return bvh;
+        }
+
         // build the BVH
         bvh.update_node_bounds(0);
-        bvh.subdivide_node(0);
+        if bvh.settings.linear {
+            bvh.build_linear();
+        } else if bvh.settings.spatial_splits {
+            bvh.build_spatial();
+        } else {
+            let builder = SahBuilder {
+                tris: &bvh.tris,
+                settings: &bvh.settings,
+                pool: ComputeTaskPool::try_get(),
+            };
+            // build in the wide layout, packed once the tree is done
+            let mut root = BvhNode::from(bvh.nodes[0]);
+            let mut nodes = vec![root];
+            builder.subdivide(&mut root, &mut bvh.triangle_indexs, &mut nodes, 0, 0);
+            nodes[0] = root;
+            bvh.nodes = nodes.into_iter().collect();
+        }
+        bvh.build_cost = bvh.sah_cost();
+        if bvh.settings.optimize_passes > 0 {
+            bvh.optimize(bvh.settings.optimize_passes);
+        }
+        if bvh.settings.wide {
+            bvh.wide = Some(WideBvh::collapse(&bvh));
+        }
+        bvh.build_time = start.elapsed();
         bvh
     }
 
-    // pub fn refit(&mut self, triangles: &[Tri]) {
-    //     for i in (0..(self.open_node - 1)).rev() {
-    //         if i != 1 {
-    //             let node = &mut self.nodes[i];
-    //             if node.is_leaf() {
-    //                 // leaf node: adjust bounds to contained triangles
-    //                 self.update_node_bounds(i, triangles);
-    //                 continue;
-    //             }
-    //             // interior node: adjust bounds to child node bounds
+    /// Updates the node bounds after the triangles have moved, keeping the tree topology.
+    ///
+    /// Much cheaper than [`Bvh::new`], but quality degrades as triangles move away from where they
+    /// were at build time, see [`Bvh::refit_quality`] and [`Bvh::needs_rebuild`].
+    pub fn refit(&mut self, triangles: &[Tri]) {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("bvh_refit").entered();
+        assert_eq!(
+            triangles.len(),
+            self.tris.len(),
+            "Refit requires the same triangle count the Bvh was built with"
+        );
+        if triangles.is_empty() {
+            return;
+        }
+        self.tris.copy_from_slice(triangles);
+
+        // children are always pushed after their parent, so walking backwards visits them first
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            if node.is_leaf() {
+                self.update_node_bounds(i);
+                continue;
+            }
+            let left = self.nodes[node.left_first as usize].aabb();
+            let right = self.nodes[(node.left_first + 1) as usize].aabb();
+            self.nodes[i].set_aabb(left.merge(&right));
+        }
+        if self.wide.is_some() {
+            self.wide = Some(WideBvh::collapse(self));
+        }
+    }
+
+    /// Refit from the current positions of the mesh, the mesh topology must not have changed
+    pub fn refit_from_mesh(&mut self, mesh: &Mesh) -> Result<(), BvhBuildError> {
+        let (triangles, _) = mesh_triangles(mesh)?;
+        if triangles.len() != self.tris.len() {
+            return Err(BvhBuildError::TriangleCountMismatch {
+                expected: self.tris.len(),
+                found: triangles.len(),
+            });
+        }
+        self.refit(&triangles);
+        Ok(())
+    }
 
-    //             let leftChild = &self.nodes[node.left_first as usize];
-    //             let rightChild = &self.nodes[(node.left_first + 1) as usize];
+    /// Total SAH cost of the tree, lower is better
+    pub fn sah_cost(&self) -> f32 {
+        if self.tris.is_empty() {
+            return 0.0;
+        }
+        let root_area = self.nodes[0].aabb().area();
+        if root_area <= 0.0 {
+            return 0.0;
+        }
+        let cost = self
+            .nodes
+            .iter()
+            .map(|node| {
+                if node.is_leaf() {
+                    node.calculate_cost()
+                } else {
+                    node.aabb().area()
+                }
+            })
+            .sum::<f32>();
+        cost / root_area
+    }
 
-    //             node.aabb_min = leftChild.aabb_min.min(rightChild.aabb_min);
-    //             node.aabb_max = leftChild.aabb_max.max(rightChild.aabb_max);
-    //         }
-    //     }
-    // }
+    /// Ratio of the current SAH cost to the cost when the tree was built, 1.0 means no degradation
+    pub fn refit_quality(&self) -> f32 {
+        if self.build_cost <= 0.0 {
+            return 1.0;
+        }
+        self.sah_cost() / self.build_cost
+    }
+
+    /// True once refitting has degraded the tree past `threshold` (e.g. 1.5 for 50% worse),
+    /// and a full rebuild with [`Bvh::new`] is worth the cost
+    pub fn needs_rebuild(&self, threshold: f32) -> bool {
+        self.refit_quality() > threshold
+    }
 
     fn update_node_bounds(&mut self, node_idx: usize) {
         let node = &mut self.nodes[node_idx];
-        node.aabb.min = Vec3A::splat(1e30f32);
-        node.aabb.max = Vec3A::splat(-1e30f32);
+        let mut aabb = Aabb3d::init();
         for i in 0..node.tri_count {
             let leaf_tri_index = self.triangle_indexs[(node.left_first + i) as usize];
             let leaf_tri = self.tris[leaf_tri_index];
-            node.aabb.expand(leaf_tri.vertex0);
-            node.aabb.expand(leaf_tri.vertex1);
-            node.aabb.expand(leaf_tri.vertex2);
+            aabb.expand(leaf_tri.vertex0);
+            aabb.expand(leaf_tri.vertex1);
+            aabb.expand(leaf_tri.vertex2);
         }
+        node.set_aabb(aabb);
     }
+}
 
-    fn subdivide_node(&mut self, node_idx: usize) {
-        let node = &self.nodes[node_idx];
+/// Bits per axis in a Morton code, 3 * 21 fits in a u64
+const MORTON_BITS: u32 = 21;
 
-        // determine split axis using SAH
-        let (axis, split_pos, split_cost) = self.find_best_split_plane(node);
-        let nosplit_cost = node.calculate_cost();
-        if split_cost >= nosplit_cost {
-            return;
+impl Bvh {
+    /// Linear build, sorts the triangles by the Morton code of their centroid then splits each
+    /// range where the highest differing bit of its codes flips
+    fn build_linear(&mut self) {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("bvh_build_linear").entered();
+
+        // quantize centroids to a grid over their bounds
+        let mut centroid_bounds = Aabb3d::init();
+        for tri in &self.tris {
+            centroid_bounds.expand(tri.centroid);
         }
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let grid = ((1 << MORTON_BITS) - 1) as f32;
+        let scale = Vec3A::select(extent.cmpgt(Vec3A::ZERO), grid / extent, Vec3A::ZERO);
+        let mut codes = self
+            .tris
+            .iter()
+            .enumerate()
+            .map(|(i, tri)| {
+                let cell = ((tri.centroid - centroid_bounds.min) * scale).as_uvec3();
+                (morton_code(cell), i)
+            })
+            .collect::<Vec<_>>();
+        codes.sort_unstable();
 
-        // in-place partition
-        let mut i = node.left_first;
-        let mut j = i + node.tri_count - 1;
-        while i <= j {
-            if self.tris[self.triangle_indexs[i as usize]].centroid[axis] < split_pos {
-                i += 1;
-            } else {
-                self.triangle_indexs.swap(i as usize, j as usize);
-                j -= 1;
-            }
+        for (slot, (_, tri_index)) in self.triangle_indexs.iter_mut().zip(&codes) {
+            *slot = *tri_index;
         }
+        let codes = codes.into_iter().map(|(code, _)| code).collect::<Vec<_>>();
+        self.emit_linear(0, &codes, 0);
+    }
 
-        // abort split if one of the sides is empty
-        let left_count = i - node.left_first;
+    /// Splits `node_idx`, whose triangles are sorted by `codes`, and fits its bounds
+    fn emit_linear(&mut self, node_idx: usize, codes: &[u64], depth: u32) {
+        let node = self.nodes[node_idx];
+        let settings = &self.settings;
+        let must_split = node.tri_count > settings.max_leaf_size;
+        if depth >= settings.max_depth || (node.tri_count <= settings.min_leaf_size && !must_split)
+        {
+            self.update_node_bounds(node_idx);
+            return;
+        }
+        let first = node.left_first as usize;
+        let count = node.tri_count as usize;
+        let range = &codes[first..first + count];
+        let left_count = linear_split(range) as u32;
+        // an empty child would be taken for an interior node, stay a leaf instead
         if left_count == 0 || left_count == node.tri_count {
+            self.update_node_bounds(node_idx);
             return;
         }
 
-        // create child nodes
-        self.nodes.push(BvhNode::default());
-        let left_child_idx = self.nodes.len() as u32 - 1;
-        self.nodes.push(BvhNode::default());
-        let right_child_idx = self.nodes.len() as u32 - 1;
-
-        self.nodes[left_child_idx as usize].left_first = self.nodes[node_idx].left_first;
-        self.nodes[left_child_idx as usize].tri_count = left_count;
-        self.nodes[right_child_idx as usize].left_first = i;
-        self.nodes[right_child_idx as usize].tri_count =
-            self.nodes[node_idx].tri_count - left_count;
-
-        self.nodes[node_idx].left_first = left_child_idx;
+        let left_child_idx = self.nodes.len();
+        self.nodes.push(BvhNode {
+            aabb: Aabb3d::init(),
+            left_first: node.left_first,
+            tri_count: left_count,
+        });
+        self.nodes.push(BvhNode {
+            aabb: Aabb3d::init(),
+            left_first: node.left_first + left_count,
+            tri_count: node.tri_count - left_count,
+        });
+        self.nodes[node_idx].left_first = left_child_idx as u32;
         self.nodes[node_idx].tri_count = 0;
 
-        self.update_node_bounds(left_child_idx as usize);
-        self.update_node_bounds(right_child_idx as usize);
+        self.emit_linear(left_child_idx, codes, depth + 1);
+        self.emit_linear(left_child_idx + 1, codes, depth + 1);
+        let left = self.nodes[left_child_idx].aabb();
+        let right = self.nodes[left_child_idx + 1].aabb();
+        self.nodes[node_idx].set_aabb(left.merge(&right));
+    }
+}
+
+/// Number of sorted `codes` in the left child, the first index where the highest bit that
+/// differs across the range is set, or the middle when all codes are equal
+fn linear_split(codes: &[u64]) -> usize {
+    let first = codes[0];
+    let last = codes[codes.len() - 1];
+    if first == last {
+        return codes.len() / 2;
+    }
+    let prefix = (first ^ last).leading_zeros();
+    codes.partition_point(|code| (first ^ code).leading_zeros() > prefix)
+}
 
-        // recurse
-        self.subdivide_node(left_child_idx as usize);
-        self.subdivide_node(right_child_idx as usize);
+/// Interleaves the low [`MORTON_BITS`] of each axis, x in the lowest bit
+fn morton_code(cell: UVec3) -> u64 {
+    fn spread(v: u32) -> u64 {
+        let mut x = v as u64 & 0x1f_ffff;
+        x = (x | x << 32) & 0x1f_0000_0000_ffff;
+        x = (x | x << 16) & 0x1f_0000_ff00_00ff;
+        x = (x | x << 8) & 0x100f_00f0_0f00_f00f;
+        x = (x | x << 4) & 0x10c3_0c30_c30c_30c3;
+        x = (x | x << 2) & 0x1249_2492_4924_9249;
+        x
     }
+    spread(cell.x) | spread(cell.y) << 1 | spread(cell.z) << 2
+}
+
+/// Subtrees with at least this many triangles are built in parallel
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+/// Binned SAH build over slices of [`Bvh::triangle_indexs`].
+///
+/// A node's descendants are always appended in the same order, whether its subtrees are built
+/// serially or in parallel, so both produce the exact same tree.
+struct SahBuilder<'a> {
+    tris: &'a [Tri],
+    settings: &'a BvhBuildSettings,
+    pool: Option<&'static ComputeTaskPool>,
+}
+
+impl SahBuilder<'_> {
+    /// Splits `node`, which covers `indexes`, pushing its descendants to `nodes`, where
+    /// `nodes_offset` is the index `nodes[0]` will have in the final tree
+    fn subdivide(
+        &self,
+        node: &mut BvhNode,
+        indexes: &mut [usize],
+        nodes: &mut Vec<BvhNode>,
+        nodes_offset: u32,
+        depth: u32,
+    ) {
+        if node.tri_count <= self.settings.min_leaf_size || depth >= self.settings.max_depth {
+            return;
+        }
+        let force_split = node.tri_count > self.settings.max_leaf_size;
 
-    fn find_best_split_plane(&self, node: &BvhNode) -> (usize, f32, f32) {
         // determine split axis using SAH
-        let mut best_axis = 0;
-        let mut split_pos = 0.0f32;
-        let mut best_cost = 1e30f32;
+        let split = self
+            .find_best_split_plane(indexes)
+            .filter(|(_, _, plane_cost)| {
+                let split_cost = self.settings.traversal_cost * node.aabb.area()
+                    + self.settings.intersection_cost * plane_cost;
+                let nosplit_cost = self.settings.intersection_cost * node.calculate_cost();
+                split_cost < nosplit_cost
+            });
+
+        let left_count = match split {
+            Some((axis, split_pos, _)) => self.partition(indexes, axis, split_pos),
+            None if force_split => self.median_split(indexes),
+            None => return,
+        };
 
-        for a in 0..3 {
-            let mut bounds_min = 1e30f32;
-            let mut bounds_max = -1e30f32;
-            for i in 0..node.tri_count {
-                let triangle = &self.tris[self.triangle_indexs[(node.left_first + i) as usize]];
-                bounds_min = bounds_min.min(triangle.centroid[a]);
-                bounds_max = bounds_max.max(triangle.centroid[a]);
+        // abort split if one of the sides is empty
+        let left_count = if left_count == 0 || left_count == node.tri_count {
+            if !force_split {
+                return;
             }
-            if bounds_min == bounds_max {
-                continue;
+            self.median_split(indexes)
+        } else {
+            left_count
+        };
+        // an empty child would be taken for an interior node, stay a leaf instead
+        if left_count == 0 || left_count == node.tri_count {
+            return;
+        }
+
+        // create child nodes
+        let indexes_len = indexes.len();
+        let (left_indexes, right_indexes) = indexes.split_at_mut(left_count as usize);
+        let mut left = BvhNode {
+            aabb: self.bounds(left_indexes),
+            left_first: node.left_first,
+            tri_count: left_count,
+        };
+        let mut right = BvhNode {
+            aabb: self.bounds(right_indexes),
+            left_first: node.left_first + left_count,
+            tri_count: node.tri_count - left_count,
+        };
+
+        // reserve the children, they are filled in once their subtrees are built
+        let left_child_idx = nodes.len();
+        nodes.push(BvhNode::default());
+        nodes.push(BvhNode::default());
+        node.left_first = nodes_offset + left_child_idx as u32;
+        node.tri_count = 0;
+
+        // recurse
+        match self.parallel_pool(indexes_len) {
+            Some(pool) => {
+                // each subtree builds into its own list, rebased once both are done
+                let mut subtrees = pool.scope(|s| {
+                    for (mut child, child_indexes) in [(left, left_indexes), (right, right_indexes)]
+                    {
+                        s.spawn(async move {
+                            let mut child_nodes = Vec::new();
+                            self.subdivide(
+                                &mut child,
+                                child_indexes,
+                                &mut child_nodes,
+                                0,
+                                depth + 1,
+                            );
+                            (child, child_nodes)
+                        });
+                    }
+                });
+                let (right, right_nodes) = subtrees.pop().unwrap();
+                let (left, left_nodes) = subtrees.pop().unwrap();
+
+                let left_base = nodes_offset + nodes.len() as u32;
+                let right_base = left_base + left_nodes.len() as u32;
+                nodes[left_child_idx] = rebase(left, left_base);
+                nodes[left_child_idx + 1] = rebase(right, right_base);
+                nodes.extend(left_nodes.into_iter().map(|n| rebase(n, left_base)));
+                nodes.extend(right_nodes.into_iter().map(|n| rebase(n, right_base)));
             }
-            // populate bins
-            let mut bin = [Bin::default(); BIN_COUNT];
-            let mut scale = BIN_COUNT as f32 / (bounds_max - bounds_min);
-            for i in 0..node.tri_count {
-                let triangle = &self.tris[self.triangle_indexs[(node.left_first + i) as usize]];
-                let bin_idx =
-                    (BIN_COUNT - 1).min(((triangle.centroid[a] - bounds_min) * scale) as usize);
-                bin[bin_idx].tri_count += 1;
-                bin[bin_idx].bounds.expand(triangle.vertex0);
-                bin[bin_idx].bounds.expand(triangle.vertex1);
-                bin[bin_idx].bounds.expand(triangle.vertex2);
+            None => {
+                self.subdivide(&mut left, left_indexes, nodes, nodes_offset, depth + 1);
+                self.subdivide(&mut right, right_indexes, nodes, nodes_offset, depth + 1);
+                nodes[left_child_idx] = left;
+                nodes[left_child_idx + 1] = right;
             }
+        }
+    }
+
+    /// The task pool to use for work over `count` triangles, if it's worth going parallel
+    fn parallel_pool(&self, count: usize) -> Option<&'static ComputeTaskPool> {
+        self.pool
+            .filter(|_| self.settings.parallel && count >= PARALLEL_BUILD_THRESHOLD)
+    }
 
-            // gather data for the BINS - 1 planes between the bins
-            let mut left_area = [0.0f32; BIN_COUNT - 1];
-            let mut right_area = [0.0f32; BIN_COUNT - 1];
-            let mut left_count = [0u32; BIN_COUNT - 1];
-            let mut right_count = [0u32; BIN_COUNT - 1];
-            let mut left_box = Aabb3d::init();
-            let mut right_box = Aabb3d::init();
-            let mut left_sum = 0u32;
-            let mut right_sum = 0u32;
-            for i in 0..(BIN_COUNT - 1) {
-                left_sum += bin[i].tri_count;
-                left_count[i] = left_sum;
-                left_box.expand_aabb(&bin[i].bounds);
-                left_area[i] = left_box.area();
-                right_sum += bin[BIN_COUNT - 1 - i].tri_count;
-                right_count[BIN_COUNT - 2 - i] = right_sum;
-                right_box.expand_aabb(&bin[BIN_COUNT - 1 - i].bounds);
-                right_area[BIN_COUNT - 2 - i] = right_box.area();
+    fn bounds(&self, indexes: &[usize]) -> Aabb3d {
+        let mut aabb = Aabb3d::init();
+        for tri_index in indexes {
+            let tri = &self.tris[*tri_index];
+            aabb.expand(tri.vertex0);
+            aabb.expand(tri.vertex1);
+            aabb.expand(tri.vertex2);
+        }
+        aabb
+    }
+
+    /// In-place partition of the triangles, returns how many ended up on the left
+    fn partition(&self, indexes: &mut [usize], axis: usize, split_pos: f32) -> u32 {
+        let mut i = 0;
+        let mut j = indexes.len();
+        while i < j {
+            if self.tris[indexes[i]].centroid[axis] < split_pos {
+                i += 1;
+            } else {
+                j -= 1;
+                indexes.swap(i, j);
             }
+        }
+        i as u32
+    }
 
-            // calculate SAH cost for the 7 planes
-            scale = (bounds_max - bounds_min) / BIN_COUNT as f32;
-            for i in 0..BIN_COUNT - 1 {
-                let plane_cost =
-                    left_count[i] as f32 * left_area[i] + right_count[i] as f32 * right_area[i];
-                if plane_cost < best_cost {
-                    best_axis = a;
-                    split_pos = bounds_min + scale * (i + 1) as f32;
-                    best_cost = plane_cost;
+    /// Splits the triangles in half along the longest centroid axis, used when a node has to be
+    /// split but SAH found no useful plane
+    fn median_split(&self, indexes: &mut [usize]) -> u32 {
+        let mut centroid_bounds = Aabb3d::init();
+        for tri_index in indexes.iter() {
+            centroid_bounds.expand(self.tris[*tri_index].centroid);
+        }
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+
+        let mid = indexes.len() / 2;
+        indexes.select_nth_unstable_by(mid, |a, b| {
+            self.tris[*a].centroid[axis].total_cmp(&self.tris[*b].centroid[axis])
+        });
+        mid as u32
+    }
+
+    /// Returns the axis, position and cost of the best split plane, if any
+    fn find_best_split_plane(&self, indexes: &[usize]) -> Option<(usize, f32, f32)> {
+        // bin each axis in parallel near the root, where nodes are largest
+        let axes = match self.parallel_pool(indexes.len()) {
+            Some(pool) => pool.scope(|s| {
+                for a in 0..3 {
+                    s.spawn(async move { self.find_best_split_on_axis(indexes, a) });
                 }
+            }),
+            None => (0..3)
+                .map(|a| self.find_best_split_on_axis(indexes, a))
+                .collect(),
+        };
+
+        let mut best = None;
+        let mut best_cost = 1e30f32;
+        for (a, split) in axes.into_iter().enumerate() {
+            if let Some((split_pos, plane_cost)) = split
+                && plane_cost < best_cost
+            {
+                best = Some((a, split_pos, plane_cost));
+                best_cost = plane_cost;
             }
         }
-        (best_axis, split_pos, best_cost)
+        best
+    }
+
+    /// Returns the position and cost of the best split plane on a single axis, if any
+    fn find_best_split_on_axis(&self, indexes: &[usize], a: usize) -> Option<(f32, f32)> {
+        let bin_count = self.settings.bin_count;
+
+        let mut bounds_min = 1e30f32;
+        let mut bounds_max = -1e30f32;
+        for tri_index in indexes {
+            let triangle = &self.tris[*tri_index];
+            bounds_min = bounds_min.min(triangle.centroid[a]);
+            bounds_max = bounds_max.max(triangle.centroid[a]);
+        }
+        if bounds_min == bounds_max {
+            return None;
+        }
+
+        // populate bins
+        let mut bin = vec![Bin::default(); bin_count];
+        let mut scale = bin_count as f32 / (bounds_max - bounds_min);
+        for tri_index in indexes {
+            let triangle = &self.tris[*tri_index];
+            let bin_idx =
+                (bin_count - 1).min(((triangle.centroid[a] - bounds_min) * scale) as usize);
+            bin[bin_idx].tri_count += 1;
+            bin[bin_idx].bounds.expand(triangle.vertex0);
+            bin[bin_idx].bounds.expand(triangle.vertex1);
+            bin[bin_idx].bounds.expand(triangle.vertex2);
+        }
+
+        // gather data for the BINS - 1 planes between the bins
+        let mut left_area = vec![0.0f32; bin_count - 1];
+        let mut right_area = vec![0.0f32; bin_count - 1];
+        let mut left_count = vec![0u32; bin_count - 1];
+        let mut right_count = vec![0u32; bin_count - 1];
+        let mut left_box = Aabb3d::init();
+        let mut right_box = Aabb3d::init();
+        let mut left_sum = 0u32;
+        let mut right_sum = 0u32;
+        for i in 0..(bin_count - 1) {
+            left_sum += bin[i].tri_count;
+            left_count[i] = left_sum;
+            left_box.expand_aabb(&bin[i].bounds);
+            left_area[i] = left_box.area();
+            right_sum += bin[bin_count - 1 - i].tri_count;
+            right_count[bin_count - 2 - i] = right_sum;
+            right_box.expand_aabb(&bin[bin_count - 1 - i].bounds);
+            right_area[bin_count - 2 - i] = right_box.area();
+        }
+
+        // calculate SAH cost for the planes
+        let mut best = None;
+        let mut best_cost = 1e30f32;
+        scale = (bounds_max - bounds_min) / bin_count as f32;
+        for i in 0..bin_count - 1 {
+            let plane_cost =
+                left_count[i] as f32 * left_area[i] + right_count[i] as f32 * right_area[i];
+            if plane_cost < best_cost {
+                best = Some((bounds_min + scale * (i + 1) as f32, plane_cost));
+                best_cost = plane_cost;
+            }
+        }
+        best
+    }
+}
+
+/// Offsets a branch node's child index by `base`, leaves index triangles and are left as is
+#[inline]
+fn rebase(mut node: BvhNode, base: u32) -> BvhNode {
+    if !node.is_leaf() {
+        node.left_first += base;
     }
+    node
 }
 
 #[derive(Default, Debug, Copy, Clone)]
@@ -1,6 +1,10 @@
 use crate::BvhSystems;
 
-use crate::tlas::{Tlas, TlasCast};
+use crate::{
+    packet::{RAY_PACKET_WIDTH, RayPacket},
+    tlas::{Tlas, TlasCast},
+    util::RayCastSettings,
+};
 
 use bevy::{
     asset::RenderAssetUsages,
@@ -31,6 +35,8 @@ pub struct BvhCamera {
     pub width: u32,
     pub height: u32,
     pub image: Option<Handle<Image>>,
+    /// Used for every ray, enable [`RayCastSettings::watertight`] to remove speckles on edges
+    pub settings: RayCastSettings,
 }
 
 impl BvhCamera {
@@ -39,6 +45,7 @@ impl BvhCamera {
             width,
             height,
             image: None,
+            settings: RayCastSettings::default(),
         }
     }
 }
@@ -51,8 +58,8 @@ pub fn init_camera_image(
     for mut camera in query.iter_mut() {
         camera.image = Some(images.add(Image::new(
             Extent3d {
-                width: camera.width as u32,
-                height: camera.height as u32,
+                width: camera.width,
+                height: camera.height,
                 depth_or_array_layers: 1,
             },
             TextureDimension::D2,
@@ -95,11 +102,8 @@ pub fn render_camera(
             const PIXEL_TILE: usize = 4 * PIXEL_TILE_COUNT;
             if let Some(data) = &mut image.data {
                 data.par_chunk_map_mut(ComputeTaskPool::get(), PIXEL_TILE, |i, pixels| {
-                    for pixel_offset in 0..(pixels.len() / 4) {
-                        // generate pixel offset and ray for this pixel
-                        let index = i * PIXEL_TILE_COUNT + pixel_offset;
-                        let offset = pixel_offset * 4;
-
+                    // generate the ray for a pixel
+                    let pixel_ray = |index: usize| {
                         let x = index as u32 % bvh_camera.width;
                         let y = index as u32 / bvh_camera.width;
 
@@ -107,20 +111,34 @@ pub fn render_camera(
                         let v = 1.0 - (y as f32 / bvh_camera.height as f32);
 
                         let direction = lower_left_corner + u * horizontal + v * vertical - origin;
-                        let ray =
-                            RayCast3d::new(origin, Dir3A::new(direction.into()).unwrap(), 1e30f32);
-
-                        // intersect the ray with the TLAS
-                        let color = if let Some((_e, hit)) = tlas_cast.intersect_tlas(&ray) {
-                            vec3(hit.u, hit.v, 1.0 - (hit.u + hit.v)) * 255.0
-                        } else {
-                            Vec3::ZERO
-                        };
-
-                        pixels[offset] = color.x as u8;
-                        pixels[offset + 1] = color.y as u8;
-                        pixels[offset + 2] = color.z as u8;
-                        pixels[offset + 3] = 255;
+                        RayCast3d::new(origin, Dir3A::new(direction.into()).unwrap(), 1e30f32)
+                    };
+
+                    // neighboring pixels are traced together as a packet
+                    for (packet_index, pixels) in
+                        pixels.chunks_mut(4 * RAY_PACKET_WIDTH).enumerate()
+                    {
+                        let first = i * PIXEL_TILE_COUNT + packet_index * RAY_PACKET_WIDTH;
+                        let count = pixels.len() / 4;
+                        let rays: [RayCast3d; RAY_PACKET_WIDTH] =
+                            std::array::from_fn(|lane| pixel_ray(first + lane.min(count - 1)));
+                        let packet = RayPacket::new(&rays[..count]);
+
+                        // intersect the packet with the TLAS
+                        let hits = tlas_cast
+                            .intersect_tlas_packet_with_settings(&packet, &bvh_camera.settings);
+                        for (pixel, hit) in pixels.chunks_mut(4).zip(hits) {
+                            let color = if let Some((_e, hit)) = hit {
+                                vec3(hit.u, hit.v, 1.0 - (hit.u + hit.v)) * 255.0
+                            } else {
+                                Vec3::ZERO
+                            };
+
+                            pixel[0] = color.x as u8;
+                            pixel[1] = color.y as u8;
+                            pixel[2] = color.z as u8;
+                            pixel[3] = 255;
+                        }
                     }
                 });
             }
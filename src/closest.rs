@@ -0,0 +1,188 @@
+use bevy::{
+    math::{Affine3A, bounding::Aabb3d},
+    prelude::*,
+};
+
+use crate::bvh::{Bvh, Tri, TriSource};
+
+/// Result of [`Bvh::closest_point`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClosestPoint {
+    /// Closest point on the surface
+    pub point: Vec3A,
+    /// Distance from the query point to `point`
+    pub distance: f32,
+    /// Index of the triangle in [`Bvh::tris`]
+    pub tri_index: usize,
+    /// Weights of the triangle's 3 vertices at `point`, in the same order as
+    /// [`Hit::barycentrics`](crate::util::Hit::barycentrics)
+    pub barycentrics: Vec3,
+    /// Where the triangle came from, `None` if the bvh wasn't built from a mesh
+    pub source: Option<TriSource>,
+}
+
+impl Bvh {
+    /// Closest point on the surface to `point`, if there is one closer than `max_distance`.
+    ///
+    /// Branch and bound over the node boxes, a smaller `max_distance` skips more of the tree.
+    /// Pass `f32::INFINITY` to always find one.
+    pub fn closest_point(&self, point: Vec3A, max_distance: f32) -> Option<ClosestPoint> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("closest_point").entered();
+        self.closest_point_in(point, max_distance, |aabb| aabb, |tri| *tri)
+    }
+
+    /// Same as [`Bvh::closest_point`] for an instance placed by `transform`, `point`,
+    /// `max_distance` and the result are in the transform's space
+    #[cfg(feature = "tlas")]
+    pub(crate) fn closest_point_transformed(
+        &self,
+        point: Vec3A,
+        max_distance: f32,
+        transform: &Affine3A,
+    ) -> Option<ClosestPoint> {
+        self.closest_point_in(
+            point,
+            max_distance,
+            |aabb| transform_aabb(&aabb, transform),
+            |tri| transform_tri(tri, transform),
+        )
+    }
+
+    /// Distances are measured after moving boxes and triangles with `node_aabb` and `node_tri`,
+    /// so non-uniform scale doesn't skew them
+    fn closest_point_in(
+        &self,
+        point: Vec3A,
+        max_distance: f32,
+        node_aabb: impl Fn(Aabb3d) -> Aabb3d,
+        node_tri: impl Fn(&Tri) -> Tri,
+    ) -> Option<ClosestPoint> {
+        if self.tris.is_empty() {
+            return None;
+        }
+        let distance_squared = |aabb: Aabb3d| {
+            let aabb = node_aabb(aabb);
+            aabb.closest_point(point).distance_squared(point)
+        };
+        let mut best: Option<ClosestPoint> = None;
+        let mut best_squared = max_distance * max_distance;
+        let mut stack = Vec::with_capacity(64);
+        let root = &self.nodes[0];
+        stack.push((root, distance_squared(root.aabb())));
+
+        while let Some((node, node_squared)) = stack.pop() {
+            // a closer point may have been found since this node was pushed
+            if node_squared >= best_squared {
+                continue;
+            }
+            if node.is_leaf() {
+                for i in 0..node.tri_count {
+                    let tri_index = self.triangle_indexs[(node.left_first + i) as usize];
+                    let tri = node_tri(&self.tris[tri_index]);
+                    let (closest, barycentrics) = closest_point_on_tri(&tri, point);
+                    let squared = closest.distance_squared(point);
+                    if squared < best_squared {
+                        best_squared = squared;
+                        best = Some(ClosestPoint {
+                            point: closest,
+                            distance: squared.sqrt(),
+                            tri_index,
+                            barycentrics,
+                            source: self.tri_sources.get(tri_index).copied(),
+                        });
+                    }
+                }
+                continue;
+            }
+
+            let child1 = &self.nodes[node.left_first as usize];
+            let child2 = &self.nodes[(node.left_first + 1) as usize];
+            let mut near = (child1, distance_squared(child1.aabb()));
+            let mut far = (child2, distance_squared(child2.aabb()));
+            if near.1 > far.1 {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // nearest child pushed last so it pops first
+            if far.1 < best_squared {
+                stack.push(far);
+            }
+            if near.1 < best_squared {
+                stack.push(near);
+            }
+        }
+        best
+    }
+}
+
+/// Box holding `aabb` after `transform`
+#[inline]
+pub(crate) fn transform_aabb(aabb: &Aabb3d, transform: &Affine3A) -> Aabb3d {
+    let center = transform.transform_point3a((aabb.min + aabb.max) * 0.5);
+    let half_size = transform.matrix3.abs() * ((aabb.max - aabb.min) * 0.5);
+    Aabb3d {
+        min: center - half_size,
+        max: center + half_size,
+    }
+}
+
+#[inline]
+pub(crate) fn transform_tri(tri: &Tri, transform: &Affine3A) -> Tri {
+    Tri::new(
+        transform.transform_point3a(tri.vertex0),
+        transform.transform_point3a(tri.vertex1),
+        transform.transform_point3a(tri.vertex2),
+    )
+}
+
+/// Closest point on a triangle and its barycentrics, by the triangle's voronoi regions from
+/// Ericson's Real-Time Collision Detection 5.1.5
+pub(crate) fn closest_point_on_tri(tri: &Tri, point: Vec3A) -> (Vec3A, Vec3) {
+    let (a, b, c) = (tri.vertex0, tri.vertex1, tri.vertex2);
+    let ab = b - a;
+    let ac = c - a;
+    let ap = point - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (a, Vec3::X);
+    }
+
+    let bp = point - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return (b, Vec3::Y);
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return (a + ab * v, vec3(1.0 - v, v, 0.0));
+    }
+
+    let cp = point - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return (c, Vec3::Z);
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return (a + ac * w, vec3(1.0 - w, 0.0, w));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return (b + (c - b) * w, vec3(0.0, 1.0 - w, w));
+    }
+
+    // inside the face
+    let denom = 1.0 / (va + vb + vc);
+    let v = vb * denom;
+    let w = vc * denom;
+    (a + ab * v + ac * w, vec3(1.0 - v - w, v, w))
+}
@@ -40,7 +40,7 @@ pub fn debug_gimos(
     bvhs: Res<Assets<Bvh>>,
     mut gizmos: Gizmos,
     bvh_debug: Res<BvhDebugMode>,
-    #[cfg(feature = "tlas")] mut tlas: ResMut<crate::tlas::Tlas>,
+    #[cfg(feature = "tlas")] tlas: Res<crate::tlas::Tlas>,
 ) {
     use bevy::color::palettes::tailwind;
 
@@ -50,13 +50,13 @@ pub fn debug_gimos(
             for (b, global_trans) in query.iter() {
                 let bvh = bvhs.get(&b.0).expect("Bvh not found");
 
-                for node in &bvh.nodes {
+                for node in bvh.nodes.iter() {
                     let color = if node.is_leaf() {
                         tailwind::GREEN_500
                     } else {
                         tailwind::YELLOW_500
                     };
-                    gizmos.cuboid(aabb3d_transform(&node.aabb, global_trans), color);
+                    gizmos.cuboid(aabb3d_transform(&node.aabb(), global_trans), color);
                 }
             }
         }
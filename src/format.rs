@@ -0,0 +1,381 @@
+use bevy::{
+    asset::{
+        AssetLoader, LoadContext,
+        io::{Reader, Writer},
+        saver::{AssetSaver, SavedAsset},
+    },
+    prelude::*,
+    tasks::futures_lite::AsyncWriteExt,
+};
+
+use crate::{
+    bvh::{Bvh, BvhBuildSettings, BvhNodes, CompactBvhNode, Tri, TriSource},
+    wide::WideBvh,
+};
+
+/// First bytes of every `.bvh` file
+pub const BVH_MAGIC: [u8; 4] = *b"RBVH";
+/// Current `.bvh` format version, bumped on any layout change
+pub const BVH_FORMAT_VERSION: u32 = 1;
+
+/// Magic, version, build settings, build cost and the four counts
+const HEADER_SIZE: usize = 4 + 4 + 40 + 4 + 16;
+const NODE_SIZE: usize = 32;
+const TRI_SIZE: usize = 36;
+const INDEX_SIZE: usize = 4;
+const TRI_SOURCE_SIZE: usize = 20;
+
+const FLAG_SPATIAL_SPLITS: u32 = 1;
+const FLAG_LINEAR: u32 = 1 << 1;
+const FLAG_WIDE: u32 = 1 << 2;
+const FLAG_PARALLEL: u32 = 1 << 3;
+
+/// Reasons a `.bvh` file can't be read
+#[derive(Debug)]
+pub enum BvhFormatError {
+    Io(std::io::Error),
+    /// The file doesn't start with [`BVH_MAGIC`]
+    InvalidMagic,
+    /// Written by a different version of the format
+    UnsupportedVersion(u32),
+    /// The file is shorter or longer than its header says, `expected` is `usize::MAX` when the
+    /// counts in the header are too large to address
+    SizeMismatch {
+        expected: usize,
+        found: usize,
+    },
+    /// A node points outside the node or triangle index lists
+    InvalidNode(usize),
+    /// A triangle index points past the triangles
+    InvalidTriangleIndex(usize),
+    /// There are triangle sources, but not one per triangle
+    TriangleSourcesMismatch {
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl std::fmt::Display for BvhFormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BvhFormatError::Io(err) => write!(f, "io error: {err}"),
+            BvhFormatError::InvalidMagic => write!(f, "not a bvh file"),
+            BvhFormatError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported bvh format version {version}, expected {BVH_FORMAT_VERSION}"
+            ),
+            BvhFormatError::SizeMismatch { expected, found } => {
+                write!(f, "expected {expected} bytes, found {found}")
+            }
+            BvhFormatError::InvalidNode(index) => write!(f, "node {index} is out of bounds"),
+            BvhFormatError::InvalidTriangleIndex(index) => {
+                write!(f, "triangle index {index} is out of bounds")
+            }
+            BvhFormatError::TriangleSourcesMismatch { expected, found } => {
+                write!(
+                    f,
+                    "expected sources for {expected} triangles, found {found}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for BvhFormatError {}
+
+impl From<std::io::Error> for BvhFormatError {
+    fn from(err: std::io::Error) -> Self {
+        BvhFormatError::Io(err)
+    }
+}
+
+impl Bvh {
+    /// Encodes the bvh in the versioned `.bvh` format, all values little endian:
+    ///
+    /// - header: [`BVH_MAGIC`], [`BVH_FORMAT_VERSION`], build settings, build cost, then the
+    ///   node, triangle, triangle index and triangle source counts
+    /// - nodes: 32 bytes each, min, `left_first`, max, `tri_count`
+    /// - triangles: 3 vertices each
+    /// - triangle indexes: `u32` each
+    /// - triangle sources: 3 vertex indexes, sub mesh and user id each, see [`Bvh::tri_sources`]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            HEADER_SIZE
+                + self.nodes.len() * NODE_SIZE
+                + self.tris.len() * TRI_SIZE
+                + self.triangle_indexs.len() * INDEX_SIZE
+                + self.tri_sources.len() * TRI_SOURCE_SIZE,
+        );
+        bytes.extend_from_slice(&BVH_MAGIC);
+        put_u32(&mut bytes, BVH_FORMAT_VERSION);
+
+        let settings = &self.settings;
+        let flags = [
+            (settings.spatial_splits, FLAG_SPATIAL_SPLITS),
+            (settings.linear, FLAG_LINEAR),
+            (settings.wide, FLAG_WIDE),
+            (settings.parallel, FLAG_PARALLEL),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        put_u32(&mut bytes, settings.bin_count as u32);
+        put_f32(&mut bytes, settings.traversal_cost);
+        put_f32(&mut bytes, settings.intersection_cost);
+        put_u32(&mut bytes, settings.min_leaf_size);
+        put_u32(&mut bytes, settings.max_leaf_size);
+        put_u32(&mut bytes, settings.max_depth);
+        put_f32(&mut bytes, settings.spatial_split_alpha);
+        put_u32(&mut bytes, settings.optimize_passes);
+        put_u32(&mut bytes, flags);
+        put_u32(&mut bytes, 0); // reserved
+        put_f32(&mut bytes, self.build_cost);
+
+        put_u32(&mut bytes, self.nodes.len() as u32);
+        put_u32(&mut bytes, self.tris.len() as u32);
+        put_u32(&mut bytes, self.triangle_indexs.len() as u32);
+        put_u32(&mut bytes, self.tri_sources.len() as u32);
+
+        for node in self.nodes.iter() {
+            put_vec3(&mut bytes, node.min);
+            put_u32(&mut bytes, node.left_first);
+            put_vec3(&mut bytes, node.max);
+            put_u32(&mut bytes, node.tri_count);
+        }
+        for tri in &self.tris {
+            put_vec3(&mut bytes, tri.vertex0.into());
+            put_vec3(&mut bytes, tri.vertex1.into());
+            put_vec3(&mut bytes, tri.vertex2.into());
+        }
+        for index in &self.triangle_indexs {
+            put_u32(&mut bytes, *index as u32);
+        }
+        for source in &self.tri_sources {
+            source
+                .vertices
+                .iter()
+                .for_each(|vertex| put_u32(&mut bytes, *vertex));
+            put_u32(&mut bytes, source.sub_mesh);
+            put_u32(&mut bytes, source.user_id);
+        }
+        bytes
+    }
+
+    /// Decodes a bvh written by [`Bvh::to_bytes`], checking the header and that every node and
+    /// index is in bounds, so a corrupt file can't cause a panic when ray casting
+    pub fn from_bytes(bytes: &[u8]) -> Result<Bvh, BvhFormatError> {
+        if bytes.len() < 8 || bytes[..4] != BVH_MAGIC {
+            return Err(BvhFormatError::InvalidMagic);
+        }
+        let mut cursor = Cursor { bytes, pos: 4 };
+        let version = cursor.u32();
+        if version != BVH_FORMAT_VERSION {
+            return Err(BvhFormatError::UnsupportedVersion(version));
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(BvhFormatError::SizeMismatch {
+                expected: HEADER_SIZE,
+                found: bytes.len(),
+            });
+        }
+
+        let mut settings = BvhBuildSettings {
+            bin_count: cursor.u32() as usize,
+            traversal_cost: cursor.f32(),
+            intersection_cost: cursor.f32(),
+            min_leaf_size: cursor.u32(),
+            max_leaf_size: cursor.u32(),
+            max_depth: cursor.u32(),
+            spatial_split_alpha: cursor.f32(),
+            optimize_passes: cursor.u32(),
+            ..default()
+        };
+        let flags = cursor.u32();
+        settings.spatial_splits = flags & FLAG_SPATIAL_SPLITS != 0;
+        settings.linear = flags & FLAG_LINEAR != 0;
+        settings.wide = flags & FLAG_WIDE != 0;
+        settings.parallel = flags & FLAG_PARALLEL != 0;
+        let _reserved = cursor.u32();
+        let build_cost = cursor.f32();
+
+        let node_count = cursor.u32() as usize;
+        let triangle_count = cursor.u32() as usize;
+        let index_count = cursor.u32() as usize;
+        let tri_source_count = cursor.u32() as usize;
+        // the counts aren't trusted yet, they can overflow on 32 bit targets
+        let expected = [
+            (node_count, NODE_SIZE),
+            (triangle_count, TRI_SIZE),
+            (index_count, INDEX_SIZE),
+            (tri_source_count, TRI_SOURCE_SIZE),
+        ]
+        .into_iter()
+        .try_fold(HEADER_SIZE, |size, (count, item_size)| {
+            count.checked_mul(item_size)?.checked_add(size)
+        })
+        .unwrap_or(usize::MAX);
+        if bytes.len() != expected {
+            return Err(BvhFormatError::SizeMismatch {
+                expected,
+                found: bytes.len(),
+            });
+        }
+
+        let mut nodes = BvhNodes::with_capacity(node_count);
+        for i in 0..node_count {
+            let min = cursor.vec3();
+            let left_first = cursor.u32();
+            let max = cursor.vec3();
+            let tri_count = cursor.u32();
+            let in_bounds = if triangle_count == 0 {
+                // nothing to traverse, an empty bvh only has its root
+                true
+            } else if tri_count > 0 {
+                left_first as usize + tri_count as usize <= index_count
+            } else {
+                // children are always after their parent
+                left_first as usize > i && left_first as usize + 1 < node_count
+            };
+            if !in_bounds {
+                return Err(BvhFormatError::InvalidNode(i));
+            }
+            nodes.push(CompactBvhNode {
+                min,
+                left_first,
+                max,
+                tri_count,
+            });
+        }
+        if triangle_count > 0 && node_count == 0 {
+            return Err(BvhFormatError::InvalidNode(0));
+        }
+
+        let tris = (0..triangle_count)
+            .map(|_| {
+                let v0 = cursor.vec3().into();
+                let v1 = cursor.vec3().into();
+                let v2 = cursor.vec3().into();
+                Tri::new(v0, v1, v2)
+            })
+            .collect::<Vec<_>>();
+
+        let mut triangle_indexs = Vec::with_capacity(index_count);
+        for i in 0..index_count {
+            let index = cursor.u32() as usize;
+            if index >= triangle_count {
+                return Err(BvhFormatError::InvalidTriangleIndex(i));
+            }
+            triangle_indexs.push(index);
+        }
+
+        // sources point into meshes that aren't stored, only the count can be checked
+        if tri_source_count != 0 && tri_source_count != triangle_count {
+            return Err(BvhFormatError::TriangleSourcesMismatch {
+                expected: triangle_count,
+                found: tri_source_count,
+            });
+        }
+        let tri_sources = (0..tri_source_count)
+            .map(|_| TriSource {
+                vertices: [cursor.u32(), cursor.u32(), cursor.u32()],
+                sub_mesh: cursor.u32(),
+                user_id: cursor.u32(),
+            })
+            .collect();
+
+        let mut bvh = Bvh {
+            nodes,
+            tris,
+            triangle_indexs,
+            build_cost,
+            settings,
+            tri_sources,
+            ..default()
+        };
+        if bvh.settings.wide {
+            bvh.wide = Some(WideBvh::collapse(&bvh));
+        }
+        Ok(bvh)
+    }
+}
+
+fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_f32(bytes: &mut Vec<u8>, value: f32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_vec3(bytes: &mut Vec<u8>, value: Vec3) {
+    put_f32(bytes, value.x);
+    put_f32(bytes, value.y);
+    put_f32(bytes, value.z);
+}
+
+/// Reads little endian values, the length is checked up front so reads can't run out
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn u32(&mut self) -> u32 {
+        let value = u32::from_le_bytes(self.bytes[self.pos..self.pos + 4].try_into().unwrap());
+        self.pos += 4;
+        value
+    }
+
+    fn f32(&mut self) -> f32 {
+        f32::from_bits(self.u32())
+    }
+
+    fn vec3(&mut self) -> Vec3 {
+        Vec3::new(self.f32(), self.f32(), self.f32())
+    }
+}
+
+/// Loads `.bvh` files written by [`BvhSaver`] or [`Bvh::to_bytes`]
+#[derive(Default)]
+pub struct BvhLoader;
+
+impl AssetLoader for BvhLoader {
+    type Asset = Bvh;
+    type Settings = ();
+    type Error = BvhFormatError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Bvh, BvhFormatError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Bvh::from_bytes(&bytes)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["bvh"]
+    }
+}
+
+/// Saves a [`Bvh`] in the `.bvh` format, for use with the asset processor
+#[derive(Default)]
+pub struct BvhSaver;
+
+impl AssetSaver for BvhSaver {
+    type Asset = Bvh;
+    type Settings = ();
+    type OutputLoader = BvhLoader;
+    type Error = std::io::Error;
+
+    async fn save(
+        &self,
+        writer: &mut Writer,
+        asset: SavedAsset<'_, Bvh>,
+        _settings: &(),
+    ) -> Result<(), std::io::Error> {
+        writer.write_all(&asset.to_bytes()).await
+    }
+}
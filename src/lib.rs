@@ -1,17 +1,36 @@
 //#![feature(test)]
 //#extern crate test;
 
+use core::time::Duration;
+
 #[allow(unused_imports)]
 #[cfg(feature = "debug_draw")]
 use bevy::color::palettes::tailwind;
 use bevy::{
-    math::bounding::{Aabb3d, BoundingVolume},
+    asset::transformer::IdentityAssetTransformer,
+    diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic},
     prelude::*,
 };
+#[cfg(feature = "helpers")]
+use bevy::{
+    platform::collections::{HashMap, HashSet},
+    tasks::{AsyncComputeTaskPool, block_on, futures_lite::future},
+};
 
 mod aabb;
+mod bake;
 mod bvh;
+mod closest;
+mod format;
+mod optimize;
+mod overlap;
+mod packet;
+mod sbvh;
+mod stats;
 mod util;
+mod wide;
+#[cfg(feature = "helpers")]
+use bake::BakedBvhs;
 use bvh::*;
 #[cfg(feature = "camera")]
 mod camera;
@@ -20,10 +39,18 @@ mod tlas;
 
 mod debug;
 
+#[cfg(feature = "tlas")]
+use bevy::{
+    math::bounding::{Aabb3d, BoundingVolume},
+    platform::time::Instant,
+};
 #[cfg(feature = "tlas")]
 use tlas::*;
 
-use crate::{aabb::Aabb3dExt, debug::BvhDebugMode};
+#[cfg(feature = "tlas")]
+use crate::aabb::Aabb3dExt;
+use crate::debug::BvhDebugMode;
+pub use stats::BvhStats;
 
 #[allow(unused_imports)]
 #[cfg(feature = "debug_draw")]
@@ -33,17 +60,20 @@ pub mod prelude {
     #[cfg(feature = "camera")]
     pub use crate::camera::*;
     pub use crate::{
-        BvhPlugin, BvhSystems, bvh::*, debug::*, util::*,
+        BvhPlugin, BvhSystems, bake::*, bvh::*, closest::*, debug::*, format::*, optimize::*,
+        packet::*, stats::*, util::*, wide::*,
     };
 
     #[cfg(feature = "tlas")]
     pub use crate::tlas::*;
 
     #[cfg(feature = "helpers")]
-    pub use crate::{ SpawnMeshBvh, SpawnSceneBvhs};
+    pub use crate::{BakedSceneBvhs, BvhBuildMode, MeshBvhError, SpawnMeshBvh, SpawnSceneBvhs};
 }
 
-const BIN_COUNT: usize = 8;
+/// Once a refit [`Bvh`] is this much worse than when built, rebuild it instead
+#[cfg(feature = "helpers")]
+const REFIT_REBUILD_THRESHOLD: f32 = 1.5;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, SystemSet)]
 pub enum BvhSystems {
@@ -54,33 +84,58 @@ pub enum BvhSystems {
 
 pub struct BvhPlugin;
 
+impl BvhPlugin {
+    /// Time in ms spent building [`Bvh`]s, measured in frames that built any
+    pub const BUILD_TIME: DiagnosticPath = DiagnosticPath::const_new("bvh/build_time");
+    /// Time in ms to rebuild the [`Tlas`]
+    #[cfg(feature = "tlas")]
+    pub const TLAS_BUILD_TIME: DiagnosticPath = DiagnosticPath::const_new("bvh/tlas_build_time");
+}
+
 impl Plugin for BvhPlugin {
     fn build(&self, app: &mut App) {
         app
             .init_resource::<BvhDebugMode>()
-            .init_asset::<Bvh>();
+            .init_asset::<Bvh>()
+            .init_asset_loader::<format::BvhLoader>()
+            .init_asset::<bake::BakedBvhs>()
+            .init_asset_loader::<bake::BakedBvhsLoader>()
+            .init_asset_loader::<bake::GltfBvhBakeLoader>()
+            // only does anything when the asset processor is enabled
+            .register_asset_processor(bake::GltfBvhProcessor::new(
+                IdentityAssetTransformer::new(),
+                bake::BakedBvhsSaver,
+            ))
+            .set_default_asset_processor::<bake::GltfBvhProcessor>("bvhs")
+            .register_diagnostic(Diagnostic::new(Self::BUILD_TIME).with_suffix("ms"))
+            .add_systems(PostUpdate, bvh_diagnostics.in_set(BvhSystems::Update));
 
         #[cfg(feature = "helpers")]
-        app.add_systems(
+        app.init_resource::<BvhBuildMode>().add_systems(
             PostUpdate,
             (
                 // Helpers to spawn BVH from Mesh3d and SceneRoot
                 spawn_mesh_bvh,
-                spawn_scene_bvhs,           
+                spawn_scene_bvhs,
+                poll_pending_mesh_bvhs,
+                // Keep the Bvhs in sync with their source meshes
+                update_mesh_bvhs,
             )
                 .chain()
-                .before(BvhSystems::Update)                    
+                .before(BvhSystems::Update),
         );
 
         #[cfg(feature = "tlas")]
         app
             .init_resource::<Tlas>()
+            .register_diagnostic(Diagnostic::new(Self::TLAS_BUILD_TIME).with_suffix("ms"))
             .add_systems(
                 PostUpdate,
-                    build_tlas.in_set(BvhSystems::Update)
+                build_tlas
+                    .in_set(BvhSystems::Update)
                     .after(TransformSystem::TransformPropagate),
             );
-        
+
         #[cfg(feature = "debug_draw")]
         app.add_systems(PostUpdate, debug::debug_gimos.after(BvhSystems::Update));
 
@@ -90,6 +145,36 @@ impl Plugin for BvhPlugin {
     }
 }
 
+/// Reports the time spent building the Bvhs added this frame
+fn bvh_diagnostics(
+    mut diagnostics: Diagnostics,
+    mut events: EventReader<AssetEvent<Bvh>>,
+    bvhs: Res<Assets<Bvh>>,
+) {
+    let build_time = events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Added { id } => bvhs.get(*id).map(|bvh| bvh.build_time),
+            _ => None,
+        })
+        .sum::<Duration>();
+    if !build_time.is_zero() {
+        diagnostics.add_measurement(&BvhPlugin::BUILD_TIME, || build_time.as_secs_f64() * 1000.0);
+    }
+}
+
+/// How the spawn helpers build their Bvhs
+#[cfg(feature = "helpers")]
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BvhBuildMode {
+    /// Build in the frame the mesh is found, big meshes will cause a hitch
+    #[default]
+    Immediate,
+    /// Build on the [`AsyncComputeTaskPool`], adding [`PendingMeshBvh`] until done. So are
+    /// rebuilds of modified meshes that can't be refit, the current bvh is kept until then
+    Async,
+}
+
 /// Marker to convert mesh3d's mesh to a bvh
 #[cfg(feature = "helpers")]
 #[derive(Component)]
@@ -101,15 +186,104 @@ fn spawn_mesh_bvh(
     mut commands: Commands,
     meshes: Res<Assets<Mesh>>,
     mut bvhs: ResMut<Assets<Bvh>>,
-    query: Query<(Entity, &Mesh3d), With<SpawnMeshBvh>>,
+    mode: Res<BvhBuildMode>,
+    query: Query<(Entity, &Mesh3d, Option<&BvhBuildSettings>), With<SpawnMeshBvh>>,
+) {
+    for (e, handle, settings) in query.iter() {
+        // wait for the mesh to load
+        let Some(mesh) = meshes.get(handle) else {
+            continue;
+        };
+        commands.entity(e).remove::<SpawnMeshBvh>();
+        let settings = settings.cloned().unwrap_or_default();
+        build_mesh_bvh(&mut commands, &mut bvhs, *mode, e, handle, mesh, settings);
+    }
+}
+
+/// Builds the bvh for a mesh entity, immediately or in the background depending on `mode`
+#[cfg(feature = "helpers")]
+fn build_mesh_bvh(
+    commands: &mut Commands,
+    bvhs: &mut Assets<Bvh>,
+    mode: BvhBuildMode,
+    e: Entity,
+    h_mesh: &Handle<Mesh>,
+    mesh: &Mesh,
+    settings: BvhBuildSettings,
+) {
+    let result = match mode {
+        BvhBuildMode::Immediate => Bvh::try_from_mesh_with_settings(mesh, settings.clone()),
+        BvhBuildMode::Async => match mesh_triangles(mesh) {
+            Ok(tris) => {
+                let task_settings = settings.clone();
+                let task = AsyncComputeTaskPool::get()
+                    .spawn(async move { Bvh::from_mesh_triangles(tris, task_settings) });
+                // replacing an earlier pending build drops its task, cancelling it
+                commands.entity(e).insert(PendingMeshBvh {
+                    task,
+                    source: h_mesh.clone(),
+                    settings,
+                });
+                return;
+            }
+            Err(err) => Err(err),
+        },
+    };
+    match result {
+        Ok(bvh) => {
+            commands
+                .entity(e)
+                .insert((MeshBvh(bvhs.add(bvh)), MeshBvhSource(h_mesh.clone())))
+                .remove::<(PendingMeshBvh, MeshBvhError)>();
+        }
+        Err(err) => {
+            error!("Failed to build Bvh for {e}: {err}");
+            // the source and settings are kept to try again once the mesh is fixed or swapped
+            commands
+                .entity(e)
+                .insert((MeshBvhError(err), MeshBvhSource(h_mesh.clone()), settings))
+                .remove::<(MeshBvh, PendingMeshBvh)>();
+        }
+    }
+}
+
+#[cfg(feature = "helpers")]
+type PendingMeshQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static mut PendingMeshBvh,
+        Option<(&'static MeshBvh, &'static MeshBvhSource)>,
+    ),
+>;
+
+/// Swaps finished background builds for a [`MeshBvh`]
+#[cfg(feature = "helpers")]
+fn poll_pending_mesh_bvhs(
+    mut commands: Commands,
+    mut bvhs: ResMut<Assets<Bvh>>,
+    mut query: PendingMeshQuery,
 ) {
-    for (e, handle) in query.iter() {
-        let mesh = meshes.get(handle).expect("Mesh not found");
-        let bvh = bvhs.add(Bvh::from(mesh));
+    for (e, mut pending, current) in query.iter_mut() {
+        let Some(mut bvh) = block_on(future::poll_once(&mut pending.task)) else {
+            continue;
+        };
+        // a rebuild of the same mesh keeps the user ids of the bvh it replaces
+        if let Some(old) = current
+            .filter(|(_, source)| source.0 == pending.source)
+            .and_then(|(mesh_bvh, _)| bvhs.get(&mesh_bvh.0))
+        {
+            let sources = std::mem::replace(&mut bvh.tri_sources, old.tri_sources.clone());
+            bvh.update_tri_sources(sources);
+        }
         commands
             .entity(e)
-            .insert(MeshBvh(bvh))
-            .remove::<SpawnMeshBvh>();
+            .insert((
+                MeshBvh(bvhs.add(bvh)),
+                MeshBvhSource(pending.source.clone()),
+            ))
+            .remove::<(PendingMeshBvh, MeshBvhError)>();
     }
 }
 
@@ -118,36 +292,101 @@ fn spawn_mesh_bvh(
 #[derive(Component)]
 pub struct SpawnSceneBvhs;
 
+/// Added to SceneRoot next to [`SpawnSceneBvhs`] to use the Bvhs baked for its glTF, see
+/// [`BakedBvhs`], meshes missing from it are built as usual. So are meshes with
+/// [`BvhBuildSettings`] of their own or on the root that differ from the ones baked with
+#[cfg(feature = "helpers")]
+#[derive(Component, Debug, Clone)]
+pub struct BakedSceneBvhs(pub Handle<BakedBvhs>);
+
+/// Added instead of [`MeshBvh`] by the helpers when the mesh can't be converted, the build is
+/// tried again when the mesh is modified or [`Mesh3d`] is swapped for another
+#[cfg(feature = "helpers")]
+#[derive(Component, Debug, Clone)]
+pub struct MeshBvhError(pub BvhBuildError);
+
+#[cfg(feature = "helpers")]
+type SceneBvhsQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static SceneRoot,
+        Option<&'static BvhBuildSettings>,
+        Option<&'static BakedSceneBvhs>,
+    ),
+    With<SpawnSceneBvhs>,
+>;
+
 /// add MeshBvh components to all Mesh3d children of SceneRoot
 #[cfg(feature = "helpers")]
 fn spawn_scene_bvhs(
     mut commands: Commands,
-    meshes: Res<Assets<Mesh>>,
-    mut bvhs: ResMut<Assets<Bvh>>,
-    query: Query<(Entity, &SceneRoot), With<SpawnSceneBvhs>>,
-    children: Query<(Entity, Option<&Children>, Option<&Mesh3d>)>,
+    (meshes, baked): (Res<Assets<Mesh>>, Res<Assets<BakedBvhs>>),
+    (mut bvhs, mode): (ResMut<Assets<Bvh>>, Res<BvhBuildMode>),
+    query: SceneBvhsQuery,
+    children: Query<(
+        Option<&Children>,
+        Option<&Mesh3d>,
+        Option<&BvhBuildSettings>,
+    )>,
     server: Res<AssetServer>,
     mut stack: Local<Vec<Entity>>,
 ) {
-    for (root, scene) in query.iter() {
-        if let Some(load_state) = server.get_load_state(scene.0.id()) {
-            if load_state.is_loading() {
-                continue;
-            }
+    for (root, scene, root_settings, opt_baked) in query.iter() {
+        if let Some(load_state) = server.get_load_state(scene.0.id())
+            && load_state.is_loading()
+        {
+            continue;
         }
+        // wait for the baked bvhs, if they failed to load everything is built instead
+        let baked = match opt_baked {
+            Some(h_baked) => match baked.get(&h_baked.0) {
+                Some(baked) => Some(baked),
+                None if server
+                    .get_load_state(h_baked.0.id())
+                    .is_some_and(|state| state.is_failed()) =>
+                {
+                    None
+                }
+                None => continue,
+            },
+            None => None,
+        };
 
         stack.push(root);
         while let Some(e) = stack.pop() {
-            let (e, opt_children, opt_mesh) = children.get(e).unwrap();
+            let (opt_children, opt_mesh, opt_settings) = children.get(e).unwrap();
             if let Some(children) = opt_children {
                 for child in children.iter() {
                     stack.push(child);
                 }
             }
             if let Some(h_mesh) = opt_mesh {
-                let mesh = meshes.get(h_mesh).expect("Mesh not found");
-                let bvh = bvhs.add(Bvh::from(mesh));
-                commands.entity(e).insert(MeshBvh(bvh));
+                // a mesh's own settings win over the scene's
+                let opt_settings = opt_settings.or(root_settings);
+                // baked bvhs share the mesh's label in the glTF, they're rebuilt when baked with
+                // other settings than the ones asked for
+                if let Some(h_bvh) = baked.and_then(|baked| {
+                    let label = h_mesh.0.path()?.label()?;
+                    baked.bvhs.get(label).filter(|h_bvh| {
+                        opt_settings.is_none_or(|settings| {
+                            bvhs.get(*h_bvh)
+                                .is_some_and(|bvh| bvh.settings == settings.clamped())
+                        })
+                    })
+                }) {
+                    commands
+                        .entity(e)
+                        .insert((MeshBvh(h_bvh.clone()), MeshBvhSource(h_mesh.0.clone())));
+                    continue;
+                }
+                let Some(mesh) = meshes.get(h_mesh) else {
+                    error!("Mesh for {e} not found, skipping Bvh");
+                    continue;
+                };
+                let settings = opt_settings.cloned().unwrap_or_default();
+                build_mesh_bvh(&mut commands, &mut bvhs, *mode, e, h_mesh, mesh, settings);
             }
         }
 
@@ -155,6 +394,166 @@ fn spawn_scene_bvhs(
     }
 }
 
+#[cfg(feature = "helpers")]
+type SwappedMeshQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static Mesh3d,
+        Option<&'static MeshBvh>,
+        Option<&'static MeshBvhSource>,
+        Option<&'static PendingMeshBvh>,
+        Option<&'static BvhBuildSettings>,
+    ),
+    (
+        Changed<Mesh3d>,
+        Or<(With<MeshBvhSource>, With<PendingMeshBvh>)>,
+    ),
+>;
+
+#[cfg(feature = "helpers")]
+type FailedMeshQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static MeshBvhSource,
+        Option<&'static BvhBuildSettings>,
+    ),
+    (With<MeshBvhError>, Without<SpawnMeshBvh>),
+>;
+
+/// Refits or rebuilds Bvhs when their source mesh is modified, and rebuilds them
+/// when an entity's Mesh3d is swapped for another mesh. Background builds of a mesh
+/// that changed since are restarted, and failed builds tried again
+#[cfg(feature = "helpers")]
+fn update_mesh_bvhs(
+    mut commands: Commands,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    (meshes, mut bvhs, mode): (Res<Assets<Mesh>>, ResMut<Assets<Bvh>>, Res<BvhBuildMode>),
+    query: Query<(Entity, &MeshBvh, &MeshBvhSource)>,
+    swapped: SwappedMeshQuery,
+    (pending, failed): (Query<(Entity, &PendingMeshBvh)>, FailedMeshQuery),
+    mut diagnostics: Diagnostics,
+) {
+    // swapped mesh handles get a new bvh, the old one may be shared with other entities
+    let mut rebuilt = HashSet::<Entity>::new();
+    for (e, h_mesh, opt_bvh, opt_source, opt_pending, opt_settings) in swapped.iter() {
+        // a pending build replaces the current bvh, so is what the mesh is compared to
+        let building = opt_pending.map(|pending| &pending.source);
+        if building.or(opt_source.map(|source| &source.0)) == Some(&h_mesh.0) {
+            continue;
+        }
+        rebuilt.insert(e);
+        // scene meshes may have been built with the root's settings, which they don't hold
+        let settings = opt_settings
+            .or(opt_pending.map(|pending| &pending.settings))
+            .or_else(|| opt_bvh.and_then(|h_bvh| bvhs.get(&h_bvh.0)).map(|bvh| &bvh.settings))
+            .cloned()
+            .unwrap_or_default();
+        let Some(mesh) = meshes.get(h_mesh) else {
+            // not loaded yet, spawn_mesh_bvh builds it once it is with the same settings, the
+            // old bvh is kept until then
+            commands
+                .entity(e)
+                .insert((SpawnMeshBvh, settings))
+                .remove::<PendingMeshBvh>();
+            continue;
+        };
+        build_mesh_bvh(&mut commands, &mut bvhs, *mode, e, h_mesh, mesh, settings);
+    }
+
+    let modified = mesh_events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    if modified.is_empty() {
+        return;
+    }
+
+    // background builds of the old geometry start over
+    for (e, pending) in pending.iter() {
+        if rebuilt.contains(&e) || !modified.contains(&pending.source.id()) {
+            continue;
+        }
+        let (h_mesh, settings) = (&pending.source, pending.settings.clone());
+        if let Some(mesh) = meshes.get(h_mesh) {
+            build_mesh_bvh(&mut commands, &mut bvhs, *mode, e, h_mesh, mesh, settings);
+        }
+    }
+
+    // a fixed mesh gets another try
+    for (e, source, opt_settings) in failed.iter() {
+        if rebuilt.contains(&e) || !modified.contains(&source.id()) {
+            continue;
+        }
+        if let Some(mesh) = meshes.get(&source.0) {
+            let settings = opt_settings.cloned().unwrap_or_default();
+            build_mesh_bvh(&mut commands, &mut bvhs, *mode, e, &source.0, mesh, settings);
+        }
+    }
+
+    // refit in place when possible, every entity sharing the bvh shares the source mesh
+    let mut updated = HashSet::<AssetId<Bvh>>::new();
+    // bvhs too degraded to refit in async mode, and the settings to rebuild them with
+    let mut background = HashMap::<AssetId<Bvh>, BvhBuildSettings>::new();
+    let mut build_time = Duration::ZERO;
+    for (e, mesh_bvh, source) in query.iter() {
+        if !modified.contains(&source.id()) {
+            continue;
+        }
+        let Some(mesh) = meshes.get(&source.0) else {
+            continue;
+        };
+        if updated.insert(mesh_bvh.id()) {
+            let Some(bvh) = bvhs.get_mut(&mesh_bvh.0) else {
+                continue;
+            };
+            let (tris, sources) = match mesh_triangles(mesh) {
+                Ok(tris) => tris,
+                Err(err) => {
+                    error!("Failed to rebuild Bvh: {err}");
+                    continue;
+                }
+            };
+            if tris.len() == bvh.tris.len() {
+                bvh.refit(&tris);
+                if !bvh.needs_rebuild(REFIT_REBUILD_THRESHOLD) {
+                    bvh.update_tri_sources(sources);
+                    continue;
+                }
+            }
+            if *mode == BvhBuildMode::Immediate {
+                let mut rebuilt = Bvh::new_with_settings(tris, bvh.settings.clone());
+                rebuilt.tri_sources = std::mem::take(&mut bvh.tri_sources);
+                rebuilt.update_tri_sources(sources);
+                *bvh = rebuilt;
+                build_time += bvh.build_time;
+                continue;
+            }
+            background.insert(mesh_bvh.id(), bvh.settings.clone());
+        }
+        // each entity gets a bvh of its own like a swapped mesh, keeping the current one until
+        // it's done, entities already building were restarted above
+        if let Some(settings) = background.get(&mesh_bvh.id())
+            && !rebuilt.contains(&e)
+            && !pending.contains(e)
+        {
+            let settings = settings.clone();
+            build_mesh_bvh(&mut commands, &mut bvhs, *mode, e, &source.0, mesh, settings);
+        }
+    }
+
+    // in place rebuilds don't add new assets, so are reported here
+    if !build_time.is_zero() {
+        diagnostics.add_measurement(&BvhPlugin::BUILD_TIME, || build_time.as_secs_f64() * 1000.0);
+    }
+}
+
 /// Builds the TLAS from the MeshBvh components in the scene
 /// Should not be called every frame, but for now it for debugging purposes
 #[cfg(feature = "tlas")]
@@ -162,7 +561,9 @@ pub fn build_tlas(
     mut tlas: ResMut<Tlas>,
     query: Query<(Entity, &MeshBvh, &GlobalTransform)>,
     bvhs: Res<Assets<Bvh>>,
+    mut diagnostics: Diagnostics,
 ) {
+    let start = Instant::now();
     let count = query.iter().count();
     let mut node_index = vec![0u32; count + 1];
     let mut node_indices = count as i32;
@@ -177,7 +578,7 @@ pub fn build_tlas(
         let bvh = bvhs.get(&b.0).expect("Bvh not found");
 
         // convert the AABB to world space
-        let local_aabb = bvh.nodes[0].aabb.clone(); // root node AABB
+        let local_aabb = bvh.nodes[0].aabb(); // root node AABB
 
         // This would be ideal, but the scale only works if the aabb is centered local space, saidly not always the case
         // let world_aabb = local_aabb
@@ -243,4 +644,8 @@ pub fn build_tlas(
         }
     }
     tlas.tlas_nodes[0] = tlas.tlas_nodes[node_index[a as usize] as usize];
+
+    diagnostics.add_measurement(&BvhPlugin::TLAS_BUILD_TIME, || {
+        start.elapsed().as_secs_f64() * 1000.0
+    });
 }
@@ -0,0 +1,160 @@
+use bevy::math::bounding::{Aabb3d, BoundingVolume};
+
+use crate::{
+    aabb::Aabb3dExt,
+    bvh::{Bvh, BvhNodes},
+    wide::WideBvh,
+};
+
+/// Result of [`Bvh::optimize`]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BvhOptimizeReport {
+    /// SAH cost before optimizing
+    pub sah_before: f32,
+    /// SAH cost after optimizing, never higher than `sah_before`
+    pub sah_after: f32,
+    /// Number of rotations applied
+    pub rotations: u32,
+    /// Number of passes run, stops early once a pass finds nothing to improve
+    pub passes: u32,
+}
+
+/// A child of a node swapped with one of its grandchildren on the other side
+#[derive(Debug, Clone, Copy)]
+struct Rotation {
+    child: usize,
+    grandchild: usize,
+    /// Surface area saved on the sibling whose grandchild moves up
+    gain: f32,
+}
+
+impl Bvh {
+    /// Improves the tree in place with tree rotations, swapping a node's child with a
+    /// grandchild whenever that shrinks the surface area of the sibling holding the grandchild.
+    ///
+    /// Much cheaper than a rebuild, works on any tree, including one degraded by
+    /// [`Bvh::refit`]. Runs up to `max_passes` passes over the tree, the triangles and leaves
+    /// are left untouched. [`Bvh::build_cost`] only goes down, so optimizing a refit tree
+    /// doesn't hide how far it has degraded from a fresh build.
+    pub fn optimize(&mut self, max_passes: u32) -> BvhOptimizeReport {
+        #[cfg(feature = "trace")]
+        let _span = bevy::prelude::info_span!("bvh_optimize").entered();
+
+        let sah_before = self.sah_cost();
+        let mut report = BvhOptimizeReport {
+            sah_before,
+            sah_after: sah_before,
+            ..Default::default()
+        };
+        if self.nodes.len() < 5 {
+            return report;
+        }
+
+        while report.passes < max_passes {
+            report.passes += 1;
+            let rotations = self.rotate_pass();
+            if rotations == 0 {
+                break;
+            }
+            report.rotations += rotations;
+            self.reorder_nodes();
+        }
+
+        if report.rotations > 0 && self.wide.is_some() {
+            self.wide = Some(WideBvh::collapse(self));
+        }
+        report.sah_after = self.sah_cost();
+        self.build_cost = self.build_cost.min(report.sah_after);
+        report
+    }
+
+    /// One bottom up pass applying the best rotation at each node, returns how many were applied
+    fn rotate_pass(&mut self) -> u32 {
+        let mut rotations = 0;
+        // children are always after their parent, so walking backwards visits them first
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            if node.is_leaf() {
+                continue;
+            }
+            let left = node.left_first as usize;
+            let right = left + 1;
+            let best = [
+                self.find_rotation(left, right),
+                self.find_rotation(right, left),
+            ]
+            .into_iter()
+            .flatten()
+            .max_by(|a, b| a.gain.total_cmp(&b.gain));
+            let Some(rotation) = best else {
+                continue;
+            };
+
+            // sibling is the parent of the grandchild, it's the only node whose bounds change
+            let sibling = if rotation.child == left { right } else { left };
+            self.nodes.swap(rotation.child, rotation.grandchild);
+            let bounds = self.children_bounds(sibling);
+            self.nodes[sibling].set_aabb(bounds);
+            rotations += 1;
+        }
+        rotations
+    }
+
+    /// Best swap of `child` with a child of `sibling`, if it shrinks `sibling`
+    fn find_rotation(&self, child: usize, sibling: usize) -> Option<Rotation> {
+        let sibling_node = &self.nodes[sibling];
+        if sibling_node.is_leaf() {
+            return None;
+        }
+        let sibling_area = sibling_node.aabb().area();
+        let first = sibling_node.left_first as usize;
+        let mut best: Option<Rotation> = None;
+        for (grandchild, other) in [(first, first + 1), (first + 1, first)] {
+            // child moves down next to the grandchild that stays
+            let area = self.nodes[child]
+                .aabb()
+                .merge(&self.nodes[other].aabb())
+                .area();
+            let gain = sibling_area - area;
+            // ignore tiny gains, float noise could otherwise rotate back and forth
+            if gain > sibling_area * 1e-4 && best.is_none_or(|b| gain > b.gain) {
+                best = Some(Rotation {
+                    child,
+                    grandchild,
+                    gain,
+                });
+            }
+        }
+        best
+    }
+
+    fn children_bounds(&self, node_idx: usize) -> Aabb3d {
+        let first = self.nodes[node_idx].left_first as usize;
+        self.nodes[first]
+            .aabb()
+            .merge(&self.nodes[first + 1].aabb())
+    }
+
+    /// Rotations move nodes between slots, lay the tree out again depth first so children are
+    /// after their parent, which [`Bvh::refit`] and the next pass rely on
+    fn reorder_nodes(&mut self) {
+        let mut nodes = BvhNodes::with_capacity(self.nodes.len());
+        nodes.push(self.nodes[0]);
+        self.reorder_children(0, &mut nodes);
+        self.nodes = nodes;
+    }
+
+    fn reorder_children(&self, new_idx: usize, nodes: &mut BvhNodes) {
+        let node = nodes[new_idx];
+        if node.is_leaf() {
+            return;
+        }
+        let first = node.left_first as usize;
+        let left_idx = nodes.len();
+        nodes[new_idx].left_first = left_idx as u32;
+        nodes.push(self.nodes[first]);
+        nodes.push(self.nodes[first + 1]);
+        self.reorder_children(left_idx, nodes);
+        self.reorder_children(left_idx + 1, nodes);
+    }
+}
@@ -0,0 +1,159 @@
+use bevy::{
+    math::{
+        Affine3A,
+        bounding::{Aabb3d, BoundingSphere, IntersectsVolume},
+    },
+    prelude::*,
+};
+
+use crate::{
+    bvh::{Bvh, Tri},
+    closest::{closest_point_on_tri, transform_aabb, transform_tri},
+};
+
+/// Volume an overlap query tests against
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum OverlapVolume {
+    Aabb(Aabb3d),
+    Sphere(BoundingSphere),
+}
+
+impl OverlapVolume {
+    #[inline]
+    pub(crate) fn overlaps_aabb(&self, aabb: &Aabb3d) -> bool {
+        match self {
+            OverlapVolume::Aabb(volume) => volume.intersects(aabb),
+            OverlapVolume::Sphere(sphere) => sphere.intersects(aabb),
+        }
+    }
+
+    #[inline]
+    fn overlaps_tri(&self, tri: &Tri) -> bool {
+        match self {
+            OverlapVolume::Aabb(aabb) => tri_overlaps_aabb(tri, aabb),
+            OverlapVolume::Sphere(sphere) => {
+                let (closest, _) = closest_point_on_tri(tri, sphere.center);
+                closest.distance_squared(sphere.center) <= sphere.radius() * sphere.radius()
+            }
+        }
+    }
+}
+
+impl Bvh {
+    /// Indices into [`Bvh::tris`] of every triangle touching `aabb`, triangles are tested
+    /// exactly, not just by their bounds
+    pub fn overlap_aabb(&self, aabb: &Aabb3d) -> Vec<usize> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("overlap_aabb").entered();
+        self.overlapping(&OverlapVolume::Aabb(*aabb), None)
+    }
+
+    /// Indices into [`Bvh::tris`] of every triangle touching `sphere`
+    pub fn overlap_sphere(&self, sphere: &BoundingSphere) -> Vec<usize> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("overlap_sphere").entered();
+        self.overlapping(&OverlapVolume::Sphere(*sphere), None)
+    }
+
+    /// Triangles touching `volume`, which is in the space `transform` moves the bvh into
+    pub(crate) fn overlapping(
+        &self,
+        volume: &OverlapVolume,
+        transform: Option<&Affine3A>,
+    ) -> Vec<usize> {
+        let mut tris = Vec::new();
+        self.visit_overlapping(volume, transform, |tri_index| {
+            tris.push(tri_index);
+            true
+        });
+        // spatial splits can reference a triangle from more than one leaf
+        if self.settings.spatial_splits {
+            tris.sort_unstable();
+            tris.dedup();
+        }
+        tris
+    }
+
+    /// Calls `visit` with each triangle touching `volume` until it returns false. With a
+    /// `transform` node boxes are moved as a whole, and triangles one by one so a rotated
+    /// instance is tested exactly
+    pub(crate) fn visit_overlapping(
+        &self,
+        volume: &OverlapVolume,
+        transform: Option<&Affine3A>,
+        mut visit: impl FnMut(usize) -> bool,
+    ) {
+        if self.tris.is_empty() {
+            return;
+        }
+        let mut stack = Vec::with_capacity(64);
+        stack.push(&self.nodes[0]);
+
+        while let Some(node) = stack.pop() {
+            let aabb = match transform {
+                Some(transform) => transform_aabb(&node.aabb(), transform),
+                None => node.aabb(),
+            };
+            if !volume.overlaps_aabb(&aabb) {
+                continue;
+            }
+            if node.is_leaf() {
+                for i in 0..node.tri_count {
+                    let tri_index = self.triangle_indexs[(node.left_first + i) as usize];
+                    let tri = &self.tris[tri_index];
+                    let overlaps = match transform {
+                        Some(transform) => volume.overlaps_tri(&transform_tri(tri, transform)),
+                        None => volume.overlaps_tri(tri),
+                    };
+                    if overlaps && !visit(tri_index) {
+                        return;
+                    }
+                }
+                continue;
+            }
+            stack.push(&self.nodes[node.left_first as usize]);
+            stack.push(&self.nodes[(node.left_first + 1) as usize]);
+        }
+    }
+}
+
+/// Separating axis test between a triangle and a box, Akenine-Möller 2001: the box's 3 axes,
+/// the triangle's normal, and the 9 cross products of their edges
+fn tri_overlaps_aabb(tri: &Tri, aabb: &Aabb3d) -> bool {
+    let center = (aabb.min + aabb.max) * 0.5;
+    let half_size = (aabb.max - aabb.min) * 0.5;
+    let v = [
+        tri.vertex0 - center,
+        tri.vertex1 - center,
+        tri.vertex2 - center,
+    ];
+
+    // box axes, the triangle's bounds against the box
+    let min = v[0].min(v[1]).min(v[2]);
+    let max = v[0].max(v[1]).max(v[2]);
+    if min.cmpgt(half_size).any() || max.cmplt(-half_size).any() {
+        return false;
+    }
+
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+
+    // triangle normal
+    let normal = edges[0].cross(edges[1]);
+    if normal.dot(v[0]).abs() > half_size.dot(normal.abs()) {
+        return false;
+    }
+
+    for edge in edges {
+        for axis in [Vec3A::X, Vec3A::Y, Vec3A::Z] {
+            let axis = axis.cross(edge);
+            let p0 = axis.dot(v[0]);
+            let p1 = axis.dot(v[1]);
+            let p2 = axis.dot(v[2]);
+            let radius = half_size.dot(axis.abs());
+            if p0.min(p1).min(p2) > radius || p0.max(p1).max(p2) < -radius {
+                return false;
+            }
+        }
+    }
+    true
+}
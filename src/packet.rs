@@ -0,0 +1,346 @@
+use std::array;
+
+use bevy::{
+    math::bounding::{Aabb3d, RayCast3d},
+    prelude::*,
+};
+
+use crate::{
+    bvh::{Bvh, Tri},
+    util::{CullMode, Hit, RayCastSettings, TriangleTest, triangle_hit},
+    wide::WIDE_BVH_WIDTH,
+};
+
+/// Number of rays in a [`RayPacket`]
+pub const RAY_PACKET_WIDTH: usize = 4;
+
+/// Up to 4 rays traced together, one per lane of glam's `Vec4`, so each box and triangle is
+/// tested once for the whole packet. Pays off for coherent rays, like camera rays from
+/// neighboring pixels or a lidar fan.
+///
+/// Lanes drop out of a subtree as soon as they miss its box.
+#[derive(Debug, Clone)]
+pub struct RayPacket {
+    /// Unused lanes hold a copy of the first ray
+    pub rays: [RayCast3d; RAY_PACKET_WIDTH],
+    /// Lanes that are traced, inactive lanes never hit anything
+    pub active: BVec4A,
+    origin: [Vec4; 3],
+    direction: [Vec4; 3],
+    recip: [Vec4; 3],
+    max: Vec4,
+}
+
+/// Per lane results of testing a [`RayPacket`] against one triangle
+struct TriangleLanes {
+    mask: BVec4A,
+    t: Vec4,
+    u: Vec4,
+    v: Vec4,
+    /// positive when the lane hits the front face
+    det: Vec4,
+}
+
+impl RayPacket {
+    /// Packs up to [`RAY_PACKET_WIDTH`] rays, only the lanes given are active
+    pub fn new(rays: &[RayCast3d]) -> Self {
+        assert!(
+            rays.len() <= RAY_PACKET_WIDTH,
+            "a ray packet holds at most {RAY_PACKET_WIDTH} rays"
+        );
+        let unused = rays
+            .first()
+            .cloned()
+            .unwrap_or_else(|| RayCast3d::new(Vec3A::ZERO, Dir3A::X, 0.0));
+        let active = BVec4A::new(
+            !rays.is_empty(),
+            rays.len() > 1,
+            rays.len() > 2,
+            rays.len() > 3,
+        );
+        let rays = array::from_fn(|lane| rays.get(lane).unwrap_or(&unused).clone());
+        Self::from_lanes(rays, active)
+    }
+
+    fn from_lanes(rays: [RayCast3d; RAY_PACKET_WIDTH], active: BVec4A) -> Self {
+        RayPacket {
+            origin: split_lanes(rays.each_ref().map(|ray| ray.origin)),
+            direction: split_lanes(rays.each_ref().map(|ray| ray.direction.as_vec3a())),
+            recip: split_lanes(rays.each_ref().map(|ray| ray.direction_recip())),
+            max: Vec4::from_array(rays.each_ref().map(|ray| ray.max)),
+            rays,
+            active,
+        }
+    }
+
+    /// Converting the packet into another space, and how much the range of each lane was
+    /// scaled by, see [`RayCastExt::to_local`](crate::util::RayCastExt::to_local)
+    pub fn to_local(&self, transform: &GlobalTransform) -> (RayPacket, Vec4) {
+        let to_local = transform.affine().inverse();
+        let mut dir_scale = Vec4::ONE;
+        let rays = array::from_fn(|lane| {
+            let ray = &self.rays[lane];
+            let local_dir = to_local.transform_vector3a(ray.direction.as_vec3a());
+            dir_scale[lane] = local_dir.length();
+            RayCast3d::new(
+                to_local.transform_point3a(ray.origin),
+                // Safety: local_dir should not be zero, as it is derived from a valid ray
+                Dir3A::new(local_dir).unwrap(),
+                ray.max * dir_scale[lane],
+            )
+        });
+        (Self::from_lanes(rays, self.active), dir_scale)
+    }
+
+    /// Intersect the packet with a BVH, returning the closest hit of each lane. Packets walk
+    /// the [`WideBvh`](crate::wide::WideBvh) when the BVH has one, the binary tree otherwise
+    pub fn intersect_bvh(&self, bvh: &Bvh) -> [Option<Hit>; RAY_PACKET_WIDTH] {
+        self.intersect_bvh_with_settings(bvh, &RayCastSettings::default())
+    }
+
+    /// Same as [`RayPacket::intersect_bvh`], with culling and epsilons from `settings`
+    pub fn intersect_bvh_with_settings(
+        &self,
+        bvh: &Bvh,
+        settings: &RayCastSettings,
+    ) -> [Option<Hit>; RAY_PACKET_WIDTH] {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("intersect_bvh_packet").entered();
+        self.intersect_bvh_lanes(bvh, settings, Vec4::splat(settings.t_min))
+    }
+
+    /// Closest hits with a `t_min` per lane, packets moved into local space scale it
+    pub(crate) fn intersect_bvh_lanes(
+        &self,
+        bvh: &Bvh,
+        settings: &RayCastSettings,
+        t_min: Vec4,
+    ) -> [Option<Hit>; RAY_PACKET_WIDTH] {
+        let mut hits = [None; RAY_PACKET_WIDTH];
+        if bvh.tris.is_empty() {
+            return hits;
+        }
+        let root = &bvh.nodes[0];
+        let (mask, near) = self.intersect_aabb(&root.aabb(), self.active, self.max);
+        if !mask.any() {
+            return hits;
+        }
+
+        // the watertight test has no packet version, its lanes are tested one at a time
+        let lane_settings: [RayCastSettings; RAY_PACKET_WIDTH] =
+            array::from_fn(|lane| RayCastSettings {
+                t_min: t_min[lane],
+                ..*settings
+            });
+        let lane_tests = settings.watertight.then(|| {
+            array::from_fn::<_, RAY_PACKET_WIDTH, _>(|lane| {
+                TriangleTest::new(&self.rays[lane], &lane_settings[lane])
+            })
+        });
+
+        let mut best = self.max;
+        let mut test_leaf = |triangle_indexs: &[usize], mask: BVec4A, best: &mut Vec4| {
+            for &tri_index in triangle_indexs {
+                let tri = &bvh.tris[tri_index];
+                if let Some(tests) = &lane_tests {
+                    for lane in (0..RAY_PACKET_WIDTH).filter(|lane| mask.test(*lane)) {
+                        if let Some(hit) = tests[lane].intersect(&self.rays[lane], tri, tri_index)
+                            && hit.distance < best[lane]
+                        {
+                            best[lane] = hit.distance;
+                            hits[lane] = Some(hit);
+                        }
+                    }
+                    continue;
+                }
+                let lanes = self.intersect_triangle(tri, mask, t_min, *best, settings);
+                if !lanes.mask.any() {
+                    continue;
+                }
+                *best = Vec4::select(lanes.mask, lanes.t, *best); // tighten the rays
+                for lane in (0..RAY_PACKET_WIDTH).filter(|lane| lanes.mask.test(*lane)) {
+                    hits[lane] = Some(triangle_hit(
+                        tri_index,
+                        lanes.t[lane],
+                        lanes.u[lane],
+                        lanes.v[lane],
+                        lanes.det[lane] > 0.0,
+                    ));
+                }
+            }
+        };
+
+        if let Some(wide) = &bvh.wide {
+            // child index and triangle count like the wide node slots, the root is a node
+            let mut stack = Vec::with_capacity(64);
+            stack.push((0, 0, mask, near));
+            while let Some((child, tri_count, mask, near)) = stack.pop() {
+                // lanes may have found closer hits since this child was pushed
+                let mask = mask & near.cmple(best);
+                if !mask.any() {
+                    continue;
+                }
+                if tri_count > 0 {
+                    let first = child as usize;
+                    test_leaf(
+                        &bvh.triangle_indexs[first..first + tri_count as usize],
+                        mask,
+                        &mut best,
+                    );
+                    continue;
+                }
+
+                // hit children pushed farthest first, going by the nearest lane, so the
+                // nearest pops next
+                let node = &wide.nodes[child as usize];
+                let mut order = [(0.0, 0, BVec4A::default(), Vec4::ZERO); WIDE_BVH_WIDTH];
+                let mut hit_count = 0;
+                for slot in 0..WIDE_BVH_WIDTH {
+                    let Some(aabb) = node.child_aabb(slot) else {
+                        continue;
+                    };
+                    let (mask, near) = self.intersect_aabb(&aabb, mask, best);
+                    if !mask.any() {
+                        continue;
+                    }
+                    let dist = nearest_lane(mask, near);
+                    let mut i = hit_count;
+                    while i > 0 && order[i - 1].0 < dist {
+                        order[i] = order[i - 1];
+                        i -= 1;
+                    }
+                    order[i] = (dist, slot, mask, near);
+                    hit_count += 1;
+                }
+                for &(_, slot, mask, near) in &order[..hit_count] {
+                    stack.push((node.child[slot], node.tri_count[slot], mask, near));
+                }
+            }
+        } else {
+            let mut stack = Vec::with_capacity(64);
+            stack.push((root, mask, near));
+            while let Some((node, mask, near)) = stack.pop() {
+                // lanes may have found closer hits since this node was pushed
+                let mask = mask & near.cmple(best);
+                if !mask.any() {
+                    continue;
+                }
+                if node.is_leaf() {
+                    let first = node.left_first as usize;
+                    test_leaf(
+                        &bvh.triangle_indexs[first..first + node.tri_count as usize],
+                        mask,
+                        &mut best,
+                    );
+                    continue;
+                }
+
+                let child1 = &bvh.nodes[node.left_first as usize];
+                let child2 = &bvh.nodes[(node.left_first + 1) as usize];
+                let (mask1, near1) = self.intersect_aabb(&child1.aabb(), mask, best);
+                let (mask2, near2) = self.intersect_aabb(&child2.aabb(), mask, best);
+                let mut first = (child1, mask1, near1);
+                let mut second = (child2, mask2, near2);
+                // nearest child first, going by the nearest lane
+                if nearest_lane(mask1, near1) > nearest_lane(mask2, near2) {
+                    std::mem::swap(&mut first, &mut second);
+                }
+                if second.1.any() {
+                    stack.push(second);
+                }
+                if first.1.any() {
+                    stack.push(first);
+                }
+            }
+        }
+        std::array::from_fn(|lane| hits[lane].map(|hit| hit.finish(&self.rays[lane], bvh)))
+    }
+
+    /// Entry distance of each lane into the box, and the lanes in `mask` that hit it before
+    /// `max`, same rules as [`RayCast3d::aabb_intersection_at`]
+    #[inline]
+    pub(crate) fn intersect_aabb(&self, aabb: &Aabb3d, mask: BVec4A, max: Vec4) -> (BVec4A, Vec4) {
+        let mut near = Vec4::ZERO;
+        let mut far = max;
+        for axis in 0..3 {
+            let t1 = (Vec4::splat(aabb.min[axis]) - self.origin[axis]) * self.recip[axis];
+            let t2 = (Vec4::splat(aabb.max[axis]) - self.origin[axis]) * self.recip[axis];
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+        }
+        (mask & near.cmple(far), near)
+    }
+
+    /// Möller-Trumbore across the lanes, same as the scalar test
+    #[inline]
+    fn intersect_triangle(
+        &self,
+        tri: &Tri,
+        mask: BVec4A,
+        t_min: Vec4,
+        best: Vec4,
+        settings: &RayCastSettings,
+    ) -> TriangleLanes {
+        let edge1 = tri.vertex1 - tri.vertex0;
+        let edge2 = tri.vertex2 - tri.vertex0;
+        let [dx, dy, dz] = self.direction;
+        // h = direction x edge2
+        let hx = dy * edge2.z - dz * edge2.y;
+        let hy = dz * edge2.x - dx * edge2.z;
+        let hz = dx * edge2.y - dy * edge2.x;
+        let det = hx * edge1.x + hy * edge1.y + hz * edge1.z;
+        let epsilon = Vec4::splat(settings.parallel_epsilon);
+        let mut mask = mask
+            & match settings.cull_mode {
+                CullMode::None => det.abs().cmpge(epsilon),
+                CullMode::Back => det.cmpge(epsilon),
+                CullMode::Front => det.cmple(-epsilon),
+            };
+
+        let f = det.recip();
+        let sx = self.origin[0] - tri.vertex0.x;
+        let sy = self.origin[1] - tri.vertex0.y;
+        let sz = self.origin[2] - tri.vertex0.z;
+        let u = f * (sx * hx + sy * hy + sz * hz);
+        // q = s x edge1
+        let qx = sy * edge1.z - sz * edge1.y;
+        let qy = sz * edge1.x - sx * edge1.z;
+        let qz = sx * edge1.y - sy * edge1.x;
+        let v = f * (dx * qx + dy * qy + dz * qz);
+        let t = f * (edge2.x * qx + edge2.y * qy + edge2.z * qz);
+        mask &= u.cmpge(Vec4::ZERO)
+            & u.cmple(Vec4::ONE)
+            & v.cmpge(Vec4::ZERO)
+            & (u + v).cmple(Vec4::ONE)
+            & t.cmpgt(t_min)
+            & t.cmplt(best);
+        TriangleLanes { mask, t, u, v, det }
+    }
+
+    /// `max` of each lane's ray
+    #[cfg(feature = "tlas")]
+    #[inline]
+    pub(crate) fn max(&self) -> Vec4 {
+        self.max
+    }
+
+    /// Shortens the ray of one lane after a hit
+    #[cfg(feature = "tlas")]
+    #[inline]
+    pub(crate) fn tighten(&mut self, lane: usize, max: f32) {
+        self.rays[lane].max = max;
+        self.max[lane] = max;
+    }
+}
+
+/// Entry distance of the nearest lane in `mask`
+#[inline]
+pub(crate) fn nearest_lane(mask: BVec4A, near: Vec4) -> f32 {
+    Vec4::select(mask, near, Vec4::INFINITY).min_element()
+}
+
+/// AoS to SoA, one `Vec4` per axis
+#[inline]
+fn split_lanes(values: [Vec3A; RAY_PACKET_WIDTH]) -> [Vec4; 3] {
+    [0, 1, 2].map(|axis| Vec4::from_array(values.map(|value| value[axis])))
+}
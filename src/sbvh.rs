@@ -0,0 +1,427 @@
+use bevy::{math::bounding::Aabb3d, prelude::*};
+
+use crate::{
+    aabb::Aabb3dExt,
+    bvh::{Bvh, BvhNode, Tri},
+};
+
+/// How many extra triangle references spatial splits may add, as a fraction of the triangle count
+const SPATIAL_SPLIT_BUDGET: f32 = 1.0;
+
+/// A reference to a triangle, spatial splits clip its bounds and can reference the same triangle
+/// from more than one leaf
+#[derive(Debug, Clone, Copy)]
+struct TriRef {
+    tri_index: usize,
+    bounds: Aabb3d,
+}
+
+impl TriRef {
+    #[inline]
+    fn centroid(&self) -> Vec3A {
+        (self.bounds.min + self.bounds.max) * 0.5
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SplitKind {
+    /// Partition references by centroid, like the regular binned SAH build
+    Object,
+    /// Partition by the plane itself, clipping references that straddle it into both children
+    Spatial,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Split {
+    kind: SplitKind,
+    axis: usize,
+    pos: f32,
+    cost: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct SpatialBin {
+    bounds: Aabb3d,
+    enter: u32,
+    exit: u32,
+}
+
+impl Default for SpatialBin {
+    fn default() -> Self {
+        SpatialBin {
+            bounds: Aabb3d::init(),
+            enter: 0,
+            exit: 0,
+        }
+    }
+}
+
+/// Spatial split BVH (SBVH) build, based on "Spatial Splits in Bounding Volume Hierarchies"
+/// by Stich et al.
+impl Bvh {
+    pub(crate) fn build_spatial(&mut self) {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("build_spatial").entered();
+        let refs = self
+            .tris
+            .iter()
+            .enumerate()
+            .map(|(tri_index, tri)| TriRef {
+                tri_index,
+                bounds: tri_bounds(tri),
+            })
+            .collect::<Vec<_>>();
+
+        // leaves append their references in order as they are created
+        self.triangle_indexs.clear();
+        let root_area = self.nodes[0].aabb().area();
+        let mut budget = (self.tris.len() as f32 * SPATIAL_SPLIT_BUDGET) as usize;
+        self.subdivide_spatial(0, refs, 0, root_area, &mut budget);
+    }
+
+    fn subdivide_spatial(
+        &mut self,
+        node_idx: usize,
+        refs: Vec<TriRef>,
+        depth: u32,
+        root_area: f32,
+        budget: &mut usize,
+    ) {
+        let mut bounds = Aabb3d::init();
+        for r in &refs {
+            bounds.expand_aabb(&r.bounds);
+        }
+        self.nodes[node_idx].set_aabb(bounds);
+
+        let count = refs.len() as u32;
+        if count <= self.settings.min_leaf_size || depth >= self.settings.max_depth {
+            self.make_leaf(node_idx, &refs);
+            return;
+        }
+        let force_split = count > self.settings.max_leaf_size;
+
+        // only try spatial splits where object split children overlap enough to matter
+        let object = self.find_object_split(&refs);
+        let overlap = object.map_or(f32::MAX, |(_, overlap)| overlap);
+        let mut best = object.map(|(split, _)| split);
+        if *budget > 0
+            && overlap / root_area > self.settings.spatial_split_alpha
+            && let Some(spatial) = self.find_spatial_split(&refs, &bounds)
+            && best.is_none_or(|b| spatial.cost < b.cost)
+        {
+            best = Some(spatial);
+        }
+
+        let best = best.filter(|split| {
+            let split_cost = self.settings.traversal_cost * bounds.area()
+                + self.settings.intersection_cost * split.cost;
+            let leaf_cost = self.settings.intersection_cost * count as f32 * bounds.area();
+            split_cost < leaf_cost
+        });
+
+        let (left, right) = match best {
+            Some(split) => self.partition_refs(refs, &split, budget),
+            None if force_split => median_split_refs(refs),
+            None => {
+                self.make_leaf(node_idx, &refs);
+                return;
+            }
+        };
+
+        // abort split if one of the sides is empty
+        let (left, right) = if left.is_empty() || right.is_empty() {
+            let mut refs = left;
+            refs.extend(right);
+            if !force_split {
+                self.make_leaf(node_idx, &refs);
+                return;
+            }
+            median_split_refs(refs)
+        } else {
+            (left, right)
+        };
+        // an empty child would be taken for an interior node, stay a leaf instead
+        if left.is_empty() || right.is_empty() {
+            let mut refs = left;
+            refs.extend(right);
+            self.make_leaf(node_idx, &refs);
+            return;
+        }
+
+        // create child nodes
+        self.nodes.push(BvhNode::default());
+        let left_child_idx = self.nodes.len() - 1;
+        self.nodes.push(BvhNode::default());
+        let right_child_idx = self.nodes.len() - 1;
+        self.nodes[node_idx].left_first = left_child_idx as u32;
+        self.nodes[node_idx].tri_count = 0;
+
+        // recurse
+        self.subdivide_spatial(left_child_idx, left, depth + 1, root_area, budget);
+        self.subdivide_spatial(right_child_idx, right, depth + 1, root_area, budget);
+    }
+
+    fn make_leaf(&mut self, node_idx: usize, refs: &[TriRef]) {
+        let node = &mut self.nodes[node_idx];
+        node.left_first = self.triangle_indexs.len() as u32;
+        node.tri_count = refs.len() as u32;
+        self.triangle_indexs
+            .extend(refs.iter().map(|r| r.tri_index));
+    }
+
+    /// Binned SAH over reference centroids, returns the best split and the surface area of the
+    /// overlap between its children
+    fn find_object_split(&self, refs: &[TriRef]) -> Option<(Split, f32)> {
+        let bin_count = self.settings.bin_count;
+        let mut best: Option<(Split, f32)> = None;
+        let mut bin = vec![(Aabb3d::init(), 0u32); bin_count];
+        let mut right_boxes = vec![Aabb3d::init(); bin_count - 1];
+        let mut right_counts = vec![0u32; bin_count - 1];
+
+        for a in 0..3 {
+            let mut bounds_min = 1e30f32;
+            let mut bounds_max = -1e30f32;
+            for r in refs {
+                bounds_min = bounds_min.min(r.centroid()[a]);
+                bounds_max = bounds_max.max(r.centroid()[a]);
+            }
+            if bounds_min == bounds_max {
+                continue;
+            }
+
+            // populate bins
+            bin.fill((Aabb3d::init(), 0));
+            let scale = bin_count as f32 / (bounds_max - bounds_min);
+            for r in refs {
+                let bin_idx =
+                    (bin_count - 1).min(((r.centroid()[a] - bounds_min) * scale) as usize);
+                bin[bin_idx].0.expand_aabb(&r.bounds);
+                bin[bin_idx].1 += 1;
+            }
+
+            // sweep from the right, then evaluate the planes sweeping from the left
+            let mut right_box = Aabb3d::init();
+            let mut right_sum = 0u32;
+            for i in (1..bin_count).rev() {
+                right_box.expand_aabb(&bin[i].0);
+                right_sum += bin[i].1;
+                right_boxes[i - 1] = right_box;
+                right_counts[i - 1] = right_sum;
+            }
+            let mut left_box = Aabb3d::init();
+            let mut left_sum = 0u32;
+            for i in 0..bin_count - 1 {
+                left_box.expand_aabb(&bin[i].0);
+                left_sum += bin[i].1;
+                if left_sum == 0 || right_counts[i] == 0 {
+                    continue;
+                }
+                let cost = left_sum as f32 * left_box.area()
+                    + right_counts[i] as f32 * right_boxes[i].area();
+                if best.is_none_or(|(b, _)| cost < b.cost) {
+                    let split = Split {
+                        kind: SplitKind::Object,
+                        axis: a,
+                        pos: bounds_min + (i + 1) as f32 / scale,
+                        cost,
+                    };
+                    best = Some((split, overlap_area(&left_box, &right_boxes[i])));
+                }
+            }
+        }
+        best
+    }
+
+    /// Binned SAH over the node bounds, chopping each reference into every bin it spans
+    fn find_spatial_split(&self, refs: &[TriRef], bounds: &Aabb3d) -> Option<Split> {
+        let bin_count = self.settings.bin_count;
+        let mut best: Option<Split> = None;
+        let mut bin = vec![SpatialBin::default(); bin_count];
+        let mut right_boxes = vec![Aabb3d::init(); bin_count - 1];
+        let mut right_counts = vec![0u32; bin_count - 1];
+
+        for a in 0..3 {
+            let extent = bounds.max[a] - bounds.min[a];
+            if extent <= 0.0 {
+                continue;
+            }
+            let bin_width = extent / bin_count as f32;
+            let bin_of = |p: f32| ((p - bounds.min[a]) / bin_width).max(0.0) as usize;
+
+            // populate bins
+            bin.fill(SpatialBin::default());
+            for r in refs {
+                let tri = &self.tris[r.tri_index];
+                let first = (bin_count - 1).min(bin_of(r.bounds.min[a]));
+                let last = first.max((bin_count - 1).min(bin_of(r.bounds.max[a])));
+                let mut remainder = r.bounds;
+                for (b, spatial_bin) in bin.iter_mut().enumerate().take(last).skip(first) {
+                    let plane = bounds.min[a] + bin_width * (b + 1) as f32;
+                    let (left, right) = split_reference(tri, &remainder, a, plane);
+                    spatial_bin.bounds.expand_aabb(&left);
+                    remainder = right;
+                }
+                bin[last].bounds.expand_aabb(&remainder);
+                bin[first].enter += 1;
+                bin[last].exit += 1;
+            }
+
+            // sweep from the right, then evaluate the planes sweeping from the left
+            let mut right_box = Aabb3d::init();
+            let mut right_sum = 0u32;
+            for i in (1..bin_count).rev() {
+                right_box.expand_aabb(&bin[i].bounds);
+                right_sum += bin[i].exit;
+                right_boxes[i - 1] = right_box;
+                right_counts[i - 1] = right_sum;
+            }
+            let mut left_box = Aabb3d::init();
+            let mut left_sum = 0u32;
+            for i in 0..bin_count - 1 {
+                left_box.expand_aabb(&bin[i].bounds);
+                left_sum += bin[i].enter;
+                if left_sum == 0 || right_counts[i] == 0 {
+                    continue;
+                }
+                let cost = left_sum as f32 * left_box.area()
+                    + right_counts[i] as f32 * right_boxes[i].area();
+                if best.is_none_or(|b| cost < b.cost) {
+                    best = Some(Split {
+                        kind: SplitKind::Spatial,
+                        axis: a,
+                        pos: bounds.min[a] + bin_width * (i + 1) as f32,
+                        cost,
+                    });
+                }
+            }
+        }
+        best
+    }
+
+    fn partition_refs(
+        &self,
+        refs: Vec<TriRef>,
+        split: &Split,
+        budget: &mut usize,
+    ) -> (Vec<TriRef>, Vec<TriRef>) {
+        let a = split.axis;
+        let mut left = Vec::with_capacity(refs.len());
+        let mut right = Vec::with_capacity(refs.len());
+        for r in refs {
+            match split.kind {
+                SplitKind::Object => {
+                    if r.centroid()[a] < split.pos {
+                        left.push(r);
+                    } else {
+                        right.push(r);
+                    }
+                }
+                SplitKind::Spatial => {
+                    if r.bounds.max[a] <= split.pos {
+                        left.push(r);
+                    } else if r.bounds.min[a] >= split.pos {
+                        right.push(r);
+                    } else {
+                        // straddles the plane, reference the triangle from both sides
+                        let (l, rb) =
+                            split_reference(&self.tris[r.tri_index], &r.bounds, a, split.pos);
+                        match (is_valid(&l), is_valid(&rb)) {
+                            (true, true) => {
+                                left.push(TriRef { bounds: l, ..r });
+                                right.push(TriRef { bounds: rb, ..r });
+                                *budget = budget.saturating_sub(1);
+                            }
+                            (true, false) => left.push(r),
+                            _ => right.push(r),
+                        }
+                    }
+                }
+            }
+        }
+        (left, right)
+    }
+}
+
+/// Splits the references in half along the longest centroid axis, used when a node has to be
+/// split but SAH found no useful plane
+fn median_split_refs(mut refs: Vec<TriRef>) -> (Vec<TriRef>, Vec<TriRef>) {
+    let mut centroid_bounds = Aabb3d::init();
+    for r in &refs {
+        centroid_bounds.expand(r.centroid());
+    }
+    let extent = centroid_bounds.max - centroid_bounds.min;
+    let axis = if extent.x > extent.y && extent.x > extent.z {
+        0
+    } else if extent.y > extent.z {
+        1
+    } else {
+        2
+    };
+    let mid = refs.len() / 2;
+    refs.select_nth_unstable_by(mid, |a, b| {
+        a.centroid()[axis].total_cmp(&b.centroid()[axis])
+    });
+    let right = refs.split_off(mid);
+    (refs, right)
+}
+
+/// Splits a triangle by an axis aligned plane, returning the bounds of each side clipped to the
+/// reference's current bounds
+fn split_reference(tri: &Tri, bounds: &Aabb3d, axis: usize, pos: f32) -> (Aabb3d, Aabb3d) {
+    let mut left = Aabb3d::init();
+    let mut right = Aabb3d::init();
+    let verts = [tri.vertex0, tri.vertex1, tri.vertex2];
+    for i in 0..3 {
+        let v0 = verts[i];
+        let v1 = verts[(i + 1) % 3];
+        let (p0, p1) = (v0[axis], v1[axis]);
+        if p0 <= pos {
+            left.expand(v0);
+        }
+        if p0 >= pos {
+            right.expand(v0);
+        }
+        // edge crosses the plane, the crossing point belongs to both sides
+        if (p0 < pos && p1 > pos) || (p0 > pos && p1 < pos) {
+            let t = ((pos - p0) / (p1 - p0)).clamp(0.0, 1.0);
+            let p = v0.lerp(v1, t);
+            left.expand(p);
+            right.expand(p);
+        }
+    }
+    left.max[axis] = pos;
+    right.min[axis] = pos;
+    (intersect(&left, bounds), intersect(&right, bounds))
+}
+
+#[inline]
+fn tri_bounds(tri: &Tri) -> Aabb3d {
+    let mut bounds = Aabb3d::init();
+    bounds.expand(tri.vertex0);
+    bounds.expand(tri.vertex1);
+    bounds.expand(tri.vertex2);
+    bounds
+}
+
+#[inline]
+fn intersect(a: &Aabb3d, b: &Aabb3d) -> Aabb3d {
+    Aabb3d {
+        min: a.min.max(b.min),
+        max: a.max.min(b.max),
+    }
+}
+
+#[inline]
+fn is_valid(aabb: &Aabb3d) -> bool {
+    aabb.min.cmple(aabb.max).all()
+}
+
+#[inline]
+fn overlap_area(a: &Aabb3d, b: &Aabb3d) -> f32 {
+    let overlap = intersect(a, b);
+    if is_valid(&overlap) {
+        overlap.area()
+    } else {
+        0.0
+    }
+}
@@ -0,0 +1,122 @@
+use std::mem::size_of;
+
+#[cfg(feature = "tlas")]
+use crate::{
+    aabb::Aabb3dExt,
+    tlas::{Tlas, TlasNode, TlasNodeType},
+};
+use crate::{
+    bvh::{Bvh, Tri},
+    wide::WideBvhNode,
+};
+
+/// Quality and memory statistics of a [`Bvh`] or `Tlas`, useful to compare
+/// build settings and catch regressions when assets change
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BvhStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    /// Fewest triangles in a leaf, instances for a Tlas
+    pub min_leaf_size: u32,
+    /// Average triangles per leaf, instances for a Tlas
+    pub avg_leaf_size: f32,
+    /// Most triangles in a leaf, instances for a Tlas
+    pub max_leaf_size: u32,
+    /// Depth of the deepest leaf, the root is depth 0
+    pub max_depth: u32,
+    /// Total SAH cost, see [`Bvh::sah_cost`]
+    pub sah_cost: f32,
+    /// Bytes allocated for the structure, including its spare capacity
+    pub memory_bytes: usize,
+}
+
+impl BvhStats {
+    fn add_leaf(&mut self, size: u32, depth: u32) {
+        if self.leaf_count == 0 || size < self.min_leaf_size {
+            self.min_leaf_size = size;
+        }
+        self.max_leaf_size = self.max_leaf_size.max(size);
+        self.max_depth = self.max_depth.max(depth);
+        self.leaf_count += 1;
+        self.avg_leaf_size += size as f32;
+    }
+
+    fn finish(&mut self) {
+        if self.leaf_count > 0 {
+            self.avg_leaf_size /= self.leaf_count as f32;
+        }
+    }
+}
+
+impl Bvh {
+    /// Walks the tree to gather its [`BvhStats`]
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats {
+            sah_cost: self.sah_cost(),
+            memory_bytes: size_of::<Bvh>()
+                + self.nodes.allocated_bytes()
+                + self.tris.capacity() * size_of::<Tri>()
+                + self.triangle_indexs.capacity() * size_of::<usize>()
+                + self
+                    .wide
+                    .as_ref()
+                    .map_or(0, |wide| wide.nodes.capacity() * size_of::<WideBvhNode>()),
+            ..Default::default()
+        };
+        if self.tris.is_empty() {
+            return stats;
+        }
+
+        let mut stack = vec![(0usize, 0u32)];
+        while let Some((node_idx, depth)) = stack.pop() {
+            let node = &self.nodes[node_idx];
+            stats.node_count += 1;
+            if node.is_leaf() {
+                stats.add_leaf(node.tri_count, depth);
+            } else {
+                let first = node.left_first as usize;
+                stack.push((first, depth + 1));
+                stack.push((first + 1, depth + 1));
+            }
+        }
+        stats.finish();
+        stats
+    }
+}
+
+#[cfg(feature = "tlas")]
+impl Tlas {
+    /// Walks the tree to gather its [`BvhStats`], each leaf holds a single instance so the leaf
+    /// sizes are always 1, the [`Bvh`]s of the instances are not included
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats {
+            memory_bytes: size_of::<Tlas>() + self.tlas_nodes.capacity() * size_of::<TlasNode>(),
+            ..Default::default()
+        };
+        // only the reserved root, nothing was added
+        if self.tlas_nodes.len() < 2 {
+            return stats;
+        }
+
+        let mut area = 0.0;
+        let mut stack = vec![(0usize, 0u32)];
+        while let Some((node_idx, depth)) = stack.pop() {
+            let node = &self.tlas_nodes[node_idx];
+            stats.node_count += 1;
+            area += node.aabb.area();
+            match node.node_type {
+                TlasNodeType::Leaf(_) => stats.add_leaf(1, depth),
+                TlasNodeType::Branch { left, right } => {
+                    stack.push((left as usize, depth + 1));
+                    stack.push((right as usize, depth + 1));
+                }
+            }
+        }
+        let root_area = self.tlas_nodes[0].aabb.area();
+        if root_area > 0.0 {
+            stats.sah_cost = area / root_area;
+        }
+        stats.finish();
+        stats
+    }
+}
@@ -2,7 +2,10 @@ use std::mem::swap;
 
 use bevy::{
     ecs::system::{SystemParam, lifetimeless::Read},
-    math::bounding::{Aabb3d, BoundingVolume, RayCast3d},
+    math::{
+        Affine3A,
+        bounding::{Aabb3d, BoundingSphere, BoundingVolume, RayCast3d},
+    },
     prelude::*,
 };
 
@@ -10,11 +13,14 @@ use crate::{
     Bvh,
     aabb::Aabb3dExt,
     bvh::MeshBvh,
-    util::{Hit, RayCastExt},
+    closest::ClosestPoint,
+    overlap::OverlapVolume,
+    packet::{RAY_PACKET_WIDTH, RayPacket, nearest_lane},
+    util::{Hit, RayCastExt, RayCastSettings},
 };
 
-/// Note: we really want this to be 32 bytes, so things layout in on nice 64 bytes cache lines, but using Vec3A instead of Vec3 in
-/// aabb, puts us at 48 instead of 32, need to test this impact more
+// Note: we really want this to be 32 bytes, so things layout in on nice 64 bytes cache lines, but using Vec3A instead of Vec3 in
+// aabb, puts us at 48 instead of 32, need to test this impact more
 // pub struct Aabb {
 //     pub min: Vec3,
 //     pub max: Vec3,
@@ -86,6 +92,31 @@ pub struct TlasCast<'w, 's> {
 
 impl<'w, 's> TlasCast<'w, 's> {
     pub fn intersect_tlas(&self, ray: &RayCast3d) -> Option<(Entity, Hit)> {
+        self.intersect_tlas_with_settings(ray, &RayCastSettings::default())
+    }
+
+    /// Same as [`TlasCast::intersect_tlas`], with culling and epsilons from `settings`,
+    /// `t_min` is in world space
+    pub fn intersect_tlas_with_settings(
+        &self,
+        ray: &RayCast3d,
+        settings: &RayCastSettings,
+    ) -> Option<(Entity, Hit)> {
+        self.intersect_tlas_filtered(ray, settings, |_| true, |_, _| true)
+    }
+
+    /// Same as [`TlasCast::intersect_tlas_with_settings`], entities `entity_filter` rejects are
+    /// skipped before their [`Bvh`] is visited, and `hit_filter` is called with each candidate
+    /// hit, still in the entity's local space, returning false ignores it and the search goes
+    /// on. For triggers, the caster's own collider or alpha tested triangles. Like
+    /// [`Bvh::intersect_filtered`], `hit_filter` can see a triangle more than once
+    pub fn intersect_tlas_filtered(
+        &self,
+        ray: &RayCast3d,
+        settings: &RayCastSettings,
+        mut entity_filter: impl FnMut(Entity) -> bool,
+        mut hit_filter: impl FnMut(Entity, &Hit) -> bool,
+    ) -> Option<(Entity, Hit)> {
         // PERF: clone the ray so we can update max distance as we find hits to tighten our search,
         // more complex the scene the bigger the performance win
         let mut ray = ray.clone();
@@ -101,24 +132,35 @@ impl<'w, 's> TlasCast<'w, 's> {
         loop {
             match node.node_type {
                 TlasNodeType::Leaf(e) => {
-                    let (_e, mesh_bvh, global_trans) = self.query.get(e).unwrap();
-                    // convert the ray to local space of the e
-                    let (local_ray, dir_scale) = ray.to_local(global_trans);
+                    // filtered instances are skipped before their bvh is visited
+                    if entity_filter(e) {
+                        let (_e, mesh_bvh, global_trans) = self.query.get(e).unwrap();
+                        // convert the ray to local space of the e
+                        let (local_ray, dir_scale) = ray.to_local(global_trans);
 
-                    // test vs bvh
-                    let bvh = self.bvhs.get(&mesh_bvh.0).unwrap();
-                    if let Some(mut hit) = local_ray.intersect_bvh(bvh) {
-                        hit.distance /= dir_scale; // Convert back to world-space distance                        
-                        if let Some(best) = best_hit {
-                            if hit.distance < best.distance {
+                        // test vs bvh
+                        let bvh = self.bvhs.get(&mesh_bvh.0).unwrap();
+                        let local_settings = RayCastSettings {
+                            t_min: settings.t_min * dir_scale,
+                            ..*settings
+                        };
+                        if let Some(hit) =
+                            bvh.intersect_filtered(&local_ray, &local_settings, |hit| {
+                                hit_filter(e, hit)
+                            })
+                        {
+                            let hit = hit.to_world(global_trans, dir_scale); // back to world space
+                            if let Some(best) = best_hit {
+                                if hit.distance < best.distance {
+                                    best_hit = Some(hit);
+                                    best_entity = Some(e);
+                                    ray.max = hit.distance; // tighten the ray
+                                }
+                            } else {
                                 best_hit = Some(hit);
                                 best_entity = Some(e);
                                 ray.max = hit.distance; // tighten the ray
                             }
-                        } else {
-                            best_hit = Some(hit);
-                            best_entity = Some(e);
-                            ray.max = hit.distance; // tighten the ray
                         }
                     }
                     if let Some(n) = stack.pop() {
@@ -151,10 +193,376 @@ impl<'w, 's> TlasCast<'w, 's> {
                 }
             }
         }
-        if let Some(hit) = best_hit {
-            Some((best_entity.unwrap(), hit))
-        } else {
-            None
+        best_hit.map(|hit| (best_entity.unwrap(), hit))
+    }
+
+    /// First hit found within `ray.max`, not necessarily the closest, stops right away.
+    /// Use it for line of sight or shadow rays
+    pub fn any_hit_tlas(&self, ray: &RayCast3d) -> Option<(Entity, Hit)> {
+        self.any_hit_tlas_with_settings(ray, &RayCastSettings::default())
+    }
+
+    /// Same as [`TlasCast::any_hit_tlas`], with culling and epsilons from `settings`,
+    /// `t_min` is in world space
+    pub fn any_hit_tlas_with_settings(
+        &self,
+        ray: &RayCast3d,
+        settings: &RayCastSettings,
+    ) -> Option<(Entity, Hit)> {
+        self.any_hit_tlas_filtered(ray, settings, |_| true, |_, _| true)
+    }
+
+    /// Same as [`TlasCast::any_hit_tlas_with_settings`], with filters like
+    /// [`TlasCast::intersect_tlas_filtered`]
+    pub fn any_hit_tlas_filtered(
+        &self,
+        ray: &RayCast3d,
+        settings: &RayCastSettings,
+        mut entity_filter: impl FnMut(Entity) -> bool,
+        mut hit_filter: impl FnMut(Entity, &Hit) -> bool,
+    ) -> Option<(Entity, Hit)> {
+        if self.tlas.tlas_nodes.is_empty() || self.query.is_empty() {
+            return None;
+        }
+        let mut stack = Vec::<&TlasNode>::with_capacity(64);
+        stack.push(&self.tlas.tlas_nodes[0]);
+
+        while let Some(node) = stack.pop() {
+            match node.node_type {
+                TlasNodeType::Leaf(e) => {
+                    if !entity_filter(e) {
+                        continue;
+                    }
+                    let (_e, mesh_bvh, global_trans) = self.query.get(e).unwrap();
+                    let (local_ray, dir_scale) = ray.to_local(global_trans);
+                    let bvh = self.bvhs.get(&mesh_bvh.0).unwrap();
+                    let local_settings = RayCastSettings {
+                        t_min: settings.t_min * dir_scale,
+                        ..*settings
+                    };
+                    if let Some(hit) =
+                        bvh.any_hit_filtered(&local_ray, &local_settings, |hit| hit_filter(e, hit))
+                    {
+                        return Some((e, hit.to_world(global_trans, dir_scale)));
+                    }
+                }
+                TlasNodeType::Branch { left, right } => {
+                    // any hit will do, so children aren't sorted
+                    for child in [left, right] {
+                        let child = &self.tlas.tlas_nodes[child as usize];
+                        if ray.aabb_intersection_at(&child.aabb).is_some() {
+                            stack.push(child);
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// True if anything is hit within `ray.max`
+    pub fn occluded(&self, ray: &RayCast3d) -> bool {
+        self.any_hit_tlas(ray).is_some()
+    }
+
+    /// Every hit within `ray.max` with the entity it belongs to, sorted by distance, with
+    /// `max_hits` only the closest ones are kept
+    pub fn intersect_tlas_all(
+        &self,
+        ray: &RayCast3d,
+        max_hits: Option<usize>,
+    ) -> Vec<(Entity, Hit)> {
+        self.intersect_tlas_all_with_settings(ray, max_hits, &RayCastSettings::default())
+    }
+
+    /// Same as [`TlasCast::intersect_tlas_all`], with culling and epsilons from `settings`,
+    /// `t_min` is in world space
+    pub fn intersect_tlas_all_with_settings(
+        &self,
+        ray: &RayCast3d,
+        max_hits: Option<usize>,
+        settings: &RayCastSettings,
+    ) -> Vec<(Entity, Hit)> {
+        self.intersect_tlas_all_filtered(ray, max_hits, settings, |_| true, |_, _| true)
+    }
+
+    /// Same as [`TlasCast::intersect_tlas_all_with_settings`], with filters like
+    /// [`TlasCast::intersect_tlas_filtered`]
+    pub fn intersect_tlas_all_filtered(
+        &self,
+        ray: &RayCast3d,
+        max_hits: Option<usize>,
+        settings: &RayCastSettings,
+        mut entity_filter: impl FnMut(Entity) -> bool,
+        mut hit_filter: impl FnMut(Entity, &Hit) -> bool,
+    ) -> Vec<(Entity, Hit)> {
+        let mut hits = Vec::new();
+        if self.tlas.tlas_nodes.is_empty() || self.query.is_empty() || max_hits == Some(0) {
+            return hits;
+        }
+        // only tightened once max_hits are found
+        let mut ray = ray.clone();
+        let mut stack = Vec::<&TlasNode>::with_capacity(64);
+        stack.push(&self.tlas.tlas_nodes[0]);
+
+        while let Some(node) = stack.pop() {
+            match node.node_type {
+                TlasNodeType::Leaf(e) => {
+                    if !entity_filter(e) {
+                        continue;
+                    }
+                    let (_e, mesh_bvh, global_trans) = self.query.get(e).unwrap();
+                    let (local_ray, dir_scale) = ray.to_local(global_trans);
+                    let bvh = self.bvhs.get(&mesh_bvh.0).unwrap();
+                    let local_settings = RayCastSettings {
+                        t_min: settings.t_min * dir_scale,
+                        ..*settings
+                    };
+                    let local_hits =
+                        bvh.intersect_all_filtered(&local_ray, max_hits, &local_settings, |hit| {
+                            hit_filter(e, hit)
+                        });
+                    hits.extend(
+                        local_hits
+                            .into_iter()
+                            .map(|hit| (e, hit.to_world(global_trans, dir_scale))),
+                    );
+                    if let Some(max_hits) = max_hits
+                        && hits.len() > max_hits
+                    {
+                        hits.sort_by(|(_, a), (_, b)| a.distance.total_cmp(&b.distance));
+                        hits.truncate(max_hits);
+                        ray.max = hits[max_hits - 1].1.distance; // tighten the ray
+                    }
+                }
+                TlasNodeType::Branch { left, right } => {
+                    // every hit is kept, so children aren't sorted
+                    for child in [left, right] {
+                        let child = &self.tlas.tlas_nodes[child as usize];
+                        if ray.aabb_intersection_at(&child.aabb).is_some() {
+                            stack.push(child);
+                        }
+                    }
+                }
+            }
+        }
+        hits.sort_by(|(_, a), (_, b)| a.distance.total_cmp(&b.distance));
+        hits
+    }
+
+    /// Closest point on any entity to `point`, if there is one closer than `max_distance`,
+    /// with the entity it's on. Everything is in world space, distances stay correct under
+    /// non-uniform scale
+    pub fn closest_point(&self, point: Vec3A, max_distance: f32) -> Option<(Entity, ClosestPoint)> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("closest_point_tlas").entered();
+        if self.tlas.tlas_nodes.is_empty() || self.query.is_empty() {
+            return None;
+        }
+        let distance = |node: &TlasNode| node.aabb.closest_point(point).distance(point);
+        let mut best: Option<(Entity, ClosestPoint)> = None;
+        let mut max_distance = max_distance;
+        let mut stack = Vec::<(&TlasNode, f32)>::with_capacity(64);
+        let root = &self.tlas.tlas_nodes[0];
+        stack.push((root, distance(root)));
+
+        while let Some((node, node_distance)) = stack.pop() {
+            // a closer point may have been found since this node was pushed
+            if node_distance >= max_distance {
+                continue;
+            }
+            match node.node_type {
+                TlasNodeType::Leaf(e) => {
+                    let (_e, mesh_bvh, global_trans) = self.query.get(e).unwrap();
+                    let bvh = self.bvhs.get(&mesh_bvh.0).unwrap();
+                    if let Some(closest) =
+                        bvh.closest_point_transformed(point, max_distance, &global_trans.affine())
+                    {
+                        max_distance = closest.distance;
+                        best = Some((e, closest));
+                    }
+                }
+                TlasNodeType::Branch { left, right } => {
+                    let child1 = &self.tlas.tlas_nodes[left as usize];
+                    let child2 = &self.tlas.tlas_nodes[right as usize];
+                    let mut near = (child1, distance(child1));
+                    let mut far = (child2, distance(child2));
+                    if near.1 > far.1 {
+                        swap(&mut near, &mut far);
+                    }
+                    // nearest child pushed last so it pops first
+                    if far.1 < max_distance {
+                        stack.push(far);
+                    }
+                    if near.1 < max_distance {
+                        stack.push(near);
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    /// Entities with a triangle touching `aabb`, in world space. Instances are tested by
+    /// their triangles, so a rotated mesh isn't matched just because its world box is
+    pub fn overlap_aabb(&self, aabb: &Aabb3d) -> Vec<Entity> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("overlap_aabb_tlas").entered();
+        self.overlap_entities(&OverlapVolume::Aabb(*aabb))
+    }
+
+    /// Entities with a triangle touching `sphere`, in world space
+    pub fn overlap_sphere(&self, sphere: &BoundingSphere) -> Vec<Entity> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("overlap_sphere_tlas").entered();
+        self.overlap_entities(&OverlapVolume::Sphere(*sphere))
+    }
+
+    /// Same as [`TlasCast::overlap_aabb`], with the indices into [`Bvh::tris`] of the
+    /// triangles touching it for each entity
+    pub fn overlap_aabb_triangles(&self, aabb: &Aabb3d) -> Vec<(Entity, Vec<usize>)> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("overlap_aabb_triangles_tlas").entered();
+        self.overlap_triangles(&OverlapVolume::Aabb(*aabb))
+    }
+
+    /// Same as [`TlasCast::overlap_sphere`], with the indices into [`Bvh::tris`] of the
+    /// triangles touching it for each entity
+    pub fn overlap_sphere_triangles(&self, sphere: &BoundingSphere) -> Vec<(Entity, Vec<usize>)> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("overlap_sphere_triangles_tlas").entered();
+        self.overlap_triangles(&OverlapVolume::Sphere(*sphere))
+    }
+
+    fn overlap_entities(&self, volume: &OverlapVolume) -> Vec<Entity> {
+        let mut entities = Vec::new();
+        self.visit_overlapping(volume, |e, bvh, transform| {
+            // one triangle is enough
+            let mut found = false;
+            bvh.visit_overlapping(volume, Some(transform), |_| {
+                found = true;
+                false
+            });
+            if found {
+                entities.push(e);
+            }
+        });
+        entities
+    }
+
+    fn overlap_triangles(&self, volume: &OverlapVolume) -> Vec<(Entity, Vec<usize>)> {
+        let mut entities = Vec::new();
+        self.visit_overlapping(volume, |e, bvh, transform| {
+            let tris = bvh.overlapping(volume, Some(transform));
+            if !tris.is_empty() {
+                entities.push((e, tris));
+            }
+        });
+        entities
+    }
+
+    /// Calls `visit` for each entity whose world box touches `volume`
+    fn visit_overlapping(
+        &self,
+        volume: &OverlapVolume,
+        mut visit: impl FnMut(Entity, &Bvh, &Affine3A),
+    ) {
+        if self.tlas.tlas_nodes.is_empty() || self.query.is_empty() {
+            return;
+        }
+        let mut stack = Vec::<&TlasNode>::with_capacity(64);
+        stack.push(&self.tlas.tlas_nodes[0]);
+        while let Some(node) = stack.pop() {
+            if !volume.overlaps_aabb(&node.aabb) {
+                continue;
+            }
+            match node.node_type {
+                TlasNodeType::Leaf(e) => {
+                    let (_e, mesh_bvh, global_trans) = self.query.get(e).unwrap();
+                    let bvh = self.bvhs.get(&mesh_bvh.0).unwrap();
+                    visit(e, bvh, &global_trans.affine());
+                }
+                TlasNodeType::Branch { left, right } => {
+                    stack.push(&self.tlas.tlas_nodes[left as usize]);
+                    stack.push(&self.tlas.tlas_nodes[right as usize]);
+                }
+            }
+        }
+    }
+
+    /// Closest hit and its entity for each lane of the packet
+    pub fn intersect_tlas_packet(
+        &self,
+        packet: &RayPacket,
+    ) -> [Option<(Entity, Hit)>; RAY_PACKET_WIDTH] {
+        self.intersect_tlas_packet_with_settings(packet, &RayCastSettings::default())
+    }
+
+    /// Same as [`TlasCast::intersect_tlas_packet`], with culling and epsilons from `settings`,
+    /// `t_min` is in world space
+    pub fn intersect_tlas_packet_with_settings(
+        &self,
+        packet: &RayPacket,
+        settings: &RayCastSettings,
+    ) -> [Option<(Entity, Hit)>; RAY_PACKET_WIDTH] {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("intersect_tlas_packet").entered();
+        let mut hits = [None; RAY_PACKET_WIDTH];
+        if self.tlas.tlas_nodes.is_empty() || self.query.is_empty() {
+            return hits;
+        }
+        // tightened per lane as hits are found
+        let mut packet = packet.clone();
+        let root = &self.tlas.tlas_nodes[0];
+        let (mask, near) = packet.intersect_aabb(&root.aabb, packet.active, packet.max());
+        let mut stack = Vec::<(&TlasNode, BVec4A, Vec4)>::with_capacity(64);
+        stack.push((root, mask, near));
+
+        while let Some((node, mask, near)) = stack.pop() {
+            // lanes may have found closer hits since this node was pushed
+            let mask = mask & near.cmple(packet.max());
+            if !mask.any() {
+                continue;
+            }
+            match node.node_type {
+                TlasNodeType::Leaf(e) => {
+                    let (_e, mesh_bvh, global_trans) = self.query.get(e).unwrap();
+                    let (mut local_packet, dir_scale) = packet.to_local(global_trans);
+                    local_packet.active = mask;
+                    let bvh = self.bvhs.get(&mesh_bvh.0).unwrap();
+                    let t_min = Vec4::splat(settings.t_min) * dir_scale;
+                    let local_hits = local_packet.intersect_bvh_lanes(bvh, settings, t_min);
+                    for (lane, hit) in local_hits.into_iter().enumerate() {
+                        let Some(hit) = hit else {
+                            continue;
+                        };
+                        let hit = hit.to_world(global_trans, dir_scale[lane]); // back to world space
+                        if hit.distance < packet.rays[lane].max {
+                            packet.tighten(lane, hit.distance);
+                            hits[lane] = Some((e, hit));
+                        }
+                    }
+                }
+                TlasNodeType::Branch { left, right } => {
+                    let child1 = &self.tlas.tlas_nodes[right as usize];
+                    let child2 = &self.tlas.tlas_nodes[left as usize];
+                    let (mask1, near1) = packet.intersect_aabb(&child1.aabb, mask, packet.max());
+                    let (mask2, near2) = packet.intersect_aabb(&child2.aabb, mask, packet.max());
+                    let mut first = (child1, mask1, near1);
+                    let mut second = (child2, mask2, near2);
+                    // nearest child first, going by the nearest lane
+                    if nearest_lane(mask1, near1) > nearest_lane(mask2, near2) {
+                        swap(&mut first, &mut second);
+                    }
+                    if second.1.any() {
+                        stack.push(second);
+                    }
+                    if first.1.any() {
+                        stack.push(first);
+                    }
+                }
+            }
         }
+        hits
     }
 }
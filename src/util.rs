@@ -1,5 +1,13 @@
-use crate::bvh::{Bvh, Tri};
-use bevy::{math::bounding::RayCast3d, prelude::*};
+use crate::{
+    bvh::{Bvh, Tri, TriSource},
+    wide::{any_hit_wide_bvh, intersect_wide_bvh},
+};
+use bevy::{
+    math::bounding::RayCast3d,
+    platform::collections::HashSet,
+    prelude::*,
+    render::mesh::{MeshVertexAttributeId, VertexAttributeValues},
+};
 use std::mem::swap;
 
 #[derive(Debug, Clone, Copy)]
@@ -8,6 +16,15 @@ pub struct Hit {
     pub u: f32,        // barycentric coordinates of the intersection
     pub v: f32,
     pub tri_index: usize,
+    /// Point on the triangle, in the space of the ray, world space from `TlasCast`
+    pub point: Vec3A,
+    /// Unit geometric normal of the triangle, on the side its vertices wind counter clockwise
+    pub normal: Vec3A,
+    /// Mesh vertices, sub mesh and user id of the triangle, if the [`Bvh`] has them, see
+    /// [`Bvh::tri_sources`]
+    pub source: Option<TriSource>,
+    /// If the ray hit the side the vertices wind counter clockwise, the side `normal` is on
+    pub front_face: bool,
 }
 
 impl Default for Hit {
@@ -17,10 +34,302 @@ impl Default for Hit {
             u: Default::default(),
             v: Default::default(),
             tri_index: Default::default(),
+            point: Default::default(),
+            normal: Default::default(),
+            source: Default::default(),
+            front_face: Default::default(),
         }
     }
 }
 
+/// Which faces of a triangle a ray can hit, front faces wind counter clockwise like Bevy's
+/// default [`Mesh`] winding
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CullMode {
+    /// Hit both faces
+    #[default]
+    None,
+    /// Ignore back faces
+    Back,
+    /// Ignore front faces
+    Front,
+}
+
+/// Options for ray casts against triangles, see [`RayCastExt::intersect_bvh_with_settings`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayCastSettings {
+    pub cull_mode: CullMode,
+    /// Hits closer than this are ignored, raise it to offset shadow or bounce rays from the
+    /// surface they start on
+    pub t_min: f32,
+    /// Rays closer to parallel with a triangle than this are treated as misses, not used by the
+    /// watertight test
+    pub parallel_epsilon: f32,
+    /// Use the watertight test from Woop et al. 2013 instead of Möller-Trumbore, rays can't slip
+    /// through the edges shared by neighboring triangles, at a small cost per ray
+    pub watertight: bool,
+}
+
+impl Default for RayCastSettings {
+    fn default() -> Self {
+        RayCastSettings {
+            cull_mode: CullMode::None,
+            t_min: 0.0001,
+            parallel_epsilon: 0.00001,
+            watertight: false,
+        }
+    }
+}
+
+/// Triangle test for one ray, set up once per query
+pub(crate) struct TriangleTest<'a> {
+    settings: &'a RayCastSettings,
+    shear: Option<RayShear>,
+}
+
+impl<'a> TriangleTest<'a> {
+    #[inline]
+    pub(crate) fn new(ray: &RayCast3d, settings: &'a RayCastSettings) -> Self {
+        TriangleTest {
+            settings,
+            shear: settings.watertight.then(|| RayShear::new(ray)),
+        }
+    }
+
+    /// Only the ray's `max` may change between calls
+    #[inline(always)]
+    pub(crate) fn intersect(&self, ray: &RayCast3d, tri: &Tri, tri_index: usize) -> Option<Hit> {
+        match &self.shear {
+            Some(shear) => watertight(ray, shear, tri, tri_index, self.settings),
+            None => moller_trumbore(ray, tri, tri_index, self.settings),
+        }
+    }
+}
+
+/// Transform putting the ray's origin at 0 and its direction along +z, from the axis the
+/// direction is largest on
+struct RayShear {
+    kx: usize,
+    ky: usize,
+    kz: usize,
+    shear: Vec3A,
+}
+
+impl RayShear {
+    fn new(ray: &RayCast3d) -> Self {
+        let dir = ray.direction.as_vec3a();
+        let abs = dir.abs();
+        let kz = if abs.x > abs.y && abs.x > abs.z {
+            0
+        } else if abs.y > abs.z {
+            1
+        } else {
+            2
+        };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        // keep the winding of triangles when looking down -z
+        if dir[kz] < 0.0 {
+            swap(&mut kx, &mut ky);
+        }
+        RayShear {
+            kx,
+            ky,
+            kz,
+            shear: vec3a(dir[kx] / dir[kz], dir[ky] / dir[kz], 1.0 / dir[kz]),
+        }
+    }
+}
+
+#[inline(always)]
+fn moller_trumbore(
+    ray: &RayCast3d,
+    tri: &Tri,
+    tri_index: usize,
+    settings: &RayCastSettings,
+) -> Option<Hit> {
+    #[cfg(feature = "trace")]
+    let _span = info_span!("intersect_triangle").entered();
+    let edge1 = tri.vertex1 - tri.vertex0;
+    let edge2 = tri.vertex2 - tri.vertex0;
+    let h = ray.direction.as_vec3a().cross(edge2);
+    // positive when the ray hits the front face
+    let a = edge1.dot(h);
+    let culled = match settings.cull_mode {
+        CullMode::None => a.abs() < settings.parallel_epsilon,
+        CullMode::Back => a < settings.parallel_epsilon,
+        CullMode::Front => a > -settings.parallel_epsilon,
+    };
+    if culled {
+        return None;
+    }
+
+    // ray parallel to triangle
+    let f = 1.0 / a;
+    let s = ray.origin - tri.vertex0;
+    let u = f * s.dot(h);
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(edge1);
+    let v = f * ray.direction.dot(q);
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = f * edge2.dot(q);
+
+    if t > settings.t_min {
+        return Some(triangle_hit(tri_index, t, u, v, a > 0.0));
+    }
+    None
+}
+
+/// Watertight ray triangle intersection, Woop, Benthin and Wald 2013. The triangle is moved into
+/// the ray's sheared space, where edge tests are exact for edges shared between triangles
+#[inline(always)]
+fn watertight(
+    ray: &RayCast3d,
+    shear: &RayShear,
+    tri: &Tri,
+    tri_index: usize,
+    settings: &RayCastSettings,
+) -> Option<Hit> {
+    #[cfg(feature = "trace")]
+    let _span = info_span!("intersect_triangle").entered();
+    let RayShear { kx, ky, kz, shear } = *shear;
+    let a = tri.vertex0 - ray.origin;
+    let b = tri.vertex1 - ray.origin;
+    let c = tri.vertex2 - ray.origin;
+    let ax = a[kx] - shear.x * a[kz];
+    let ay = a[ky] - shear.y * a[kz];
+    let bx = b[kx] - shear.x * b[kz];
+    let by = b[ky] - shear.y * b[kz];
+    let cx = c[kx] - shear.x * c[kz];
+    let cy = c[ky] - shear.y * c[kz];
+
+    // scaled barycentrics, redone in double precision when an edge passes through the ray
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+        v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+        w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+    }
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    // positive when the ray hits the front face
+    let det = u + v + w;
+    let culled = match settings.cull_mode {
+        CullMode::None => det == 0.0,
+        CullMode::Back => det <= 0.0,
+        CullMode::Front => det >= 0.0,
+    };
+    if culled {
+        return None;
+    }
+
+    let t = (u * shear.z * a[kz] + v * shear.z * b[kz] + w * shear.z * c[kz]) / det;
+    if t > settings.t_min {
+        return Some(triangle_hit(tri_index, t, v / det, w / det, det > 0.0));
+    }
+    None
+}
+
+/// Candidate hit, only what the triangle test found, see [`Hit::finish`]
+#[inline(always)]
+pub(crate) fn triangle_hit(tri_index: usize, t: f32, u: f32, v: f32, front_face: bool) -> Hit {
+    Hit {
+        distance: t,
+        u,
+        v,
+        tri_index,
+        front_face,
+        ..default()
+    }
+}
+
+impl Hit {
+    /// Weights of the triangle's 3 vertices at the hit
+    #[inline]
+    pub fn barycentrics(&self) -> Vec3 {
+        vec3(1.0 - self.u - self.v, self.u, self.v)
+    }
+
+    /// Fills in the point and normal, left out of the triangle tests since most candidate hits
+    /// are beaten by a closer one
+    #[inline]
+    pub(crate) fn with_surface(mut self, ray: &RayCast3d, tri: &Tri) -> Hit {
+        self.point = ray.get_point(self.distance);
+        self.normal = (tri.vertex1 - tri.vertex0)
+            .cross(tri.vertex2 - tri.vertex0)
+            .normalize_or_zero();
+        self
+    }
+
+    /// Fills in the point, normal and where the triangle came from, done once a candidate hit
+    /// is closer than the best so far, before it's filtered
+    #[inline]
+    pub(crate) fn finish(self, ray: &RayCast3d, bvh: &Bvh) -> Hit {
+        let mut hit = self.with_surface(ray, &bvh.tris[self.tri_index]);
+        hit.source = bvh.tri_sources.get(hit.tri_index).copied();
+        hit
+    }
+
+    /// Converts a hit from a ray made by [`RayCastExt::to_local`] back to world space, normals
+    /// use the inverse transpose so they stay perpendicular under non-uniform scale
+    pub fn to_world(mut self, transform: &GlobalTransform, dir_scale: f32) -> Hit {
+        let affine = transform.affine();
+        self.distance /= dir_scale;
+        self.point = affine.transform_point3a(self.point);
+        self.normal = (affine.matrix3.inverse().transpose() * self.normal).normalize_or_zero();
+        self
+    }
+
+    /// Interpolates a vertex attribute of the mesh the hit triangle came from, for example
+    /// [`Mesh::ATTRIBUTE_NORMAL`], [`Mesh::ATTRIBUTE_UV_0`] or [`Mesh::ATTRIBUTE_COLOR`], for
+    /// sub meshes pass the mesh of [`TriSource::sub_mesh`].
+    ///
+    /// Values are in mesh space, components the attribute doesn't have are 0. Returns `None` if
+    /// the bvh wasn't built from a mesh, or the mesh doesn't have the attribute in a float or
+    /// normalized format.
+    pub fn interpolate(
+        &self,
+        mesh: &Mesh,
+        attribute: impl Into<MeshVertexAttributeId>,
+    ) -> Option<Vec4> {
+        let vertices = self.source?.vertices;
+        let values = mesh.attribute(attribute)?;
+        let weights = self.barycentrics();
+        let mut value = Vec4::ZERO;
+        for (vertex, weight) in vertices.into_iter().zip(weights.to_array()) {
+            value += vertex_value(values, vertex as usize)? * weight;
+        }
+        Some(value)
+    }
+}
+
+/// Reads one vertex of an attribute, normalized formats are mapped to their float range
+fn vertex_value(values: &VertexAttributeValues, index: usize) -> Option<Vec4> {
+    let unorm8 = |v: u8| v as f32 / u8::MAX as f32;
+    let unorm16 = |v: u16| v as f32 / u16::MAX as f32;
+    let value = match values {
+        VertexAttributeValues::Float32(v) => vec4(*v.get(index)?, 0.0, 0.0, 0.0),
+        VertexAttributeValues::Float32x2(v) => Vec2::from(*v.get(index)?).extend(0.0).extend(0.0),
+        VertexAttributeValues::Float32x3(v) => Vec3::from(*v.get(index)?).extend(0.0),
+        VertexAttributeValues::Float32x4(v) => Vec4::from(*v.get(index)?),
+        VertexAttributeValues::Unorm16x2(v) => {
+            let [x, y] = *v.get(index)?;
+            vec4(unorm16(x), unorm16(y), 0.0, 0.0)
+        }
+        VertexAttributeValues::Unorm16x4(v) => Vec4::from(v.get(index)?.map(unorm16)),
+        VertexAttributeValues::Unorm8x4(v) => Vec4::from(v.get(index)?.map(unorm8)),
+        _ => return None,
+    };
+    Some(value)
+}
+
 pub trait RayCastExt {
     /// Converting ray into another space, and how much the range was scaled by
     fn to_local(&self, transform: &GlobalTransform) -> (RayCast3d, f32);
@@ -30,8 +339,41 @@ pub trait RayCastExt {
     /// Intersect a triangle with the ray, returning the hit information if it intersects
     fn intersect_triangle(&self, tri: &Tri, tri_index: usize) -> Option<Hit>;
 
+    /// Same as [`RayCastExt::intersect_triangle`], with culling and epsilons from `settings`
+    fn intersect_triangle_with_settings(
+        &self,
+        tri: &Tri,
+        tri_index: usize,
+        settings: &RayCastSettings,
+    ) -> Option<Hit>;
+
     /// Intersect the ray with a BVH, returning the closest hit if any
     fn intersect_bvh(&self, bvh: &Bvh) -> Option<Hit>;
+
+    /// Same as [`RayCastExt::intersect_bvh`], with culling and epsilons from `settings`
+    fn intersect_bvh_with_settings(&self, bvh: &Bvh, settings: &RayCastSettings) -> Option<Hit>;
+
+    /// Intersect the ray with a BVH, returning the first hit found within `max`, not
+    /// necessarily the closest. Stops right away, use it for line of sight or shadow rays
+    fn any_hit_bvh(&self, bvh: &Bvh) -> Option<Hit>;
+
+    /// Same as [`RayCastExt::any_hit_bvh`], with culling and epsilons from `settings`
+    fn any_hit_bvh_with_settings(&self, bvh: &Bvh, settings: &RayCastSettings) -> Option<Hit>;
+
+    /// True if any triangle of the BVH is hit within `max`
+    fn occluded(&self, bvh: &Bvh) -> bool;
+
+    /// Every hit of the ray with a BVH within `max`, sorted by distance, with `max_hits` only
+    /// the closest ones are kept
+    fn intersect_bvh_all(&self, bvh: &Bvh, max_hits: Option<usize>) -> Vec<Hit>;
+
+    /// Same as [`RayCastExt::intersect_bvh_all`], with culling and epsilons from `settings`
+    fn intersect_bvh_all_with_settings(
+        &self,
+        bvh: &Bvh,
+        max_hits: Option<usize>,
+        settings: &RayCastSettings,
+    ) -> Vec<Hit>;
 }
 
 impl RayCastExt for RayCast3d {
@@ -63,63 +405,95 @@ impl RayCastExt for RayCast3d {
 
     #[inline(always)]
     fn intersect_triangle(&self, tri: &Tri, tri_index: usize) -> Option<Hit> {
-        #[cfg(feature = "trace")]
-        let _span = info_span!("intersect_triangle").entered();
-        let edge1 = tri.vertex1 - tri.vertex0;
-        let edge2 = tri.vertex2 - tri.vertex0;
-        let h = self.direction.as_vec3a().cross(edge2);
-        let a = edge1.dot(h);
-        if a.abs() < 0.00001 {
-            return None;
-        }
-
-        // ray parallel to triangle
-        let f = 1.0 / a;
-        let s = self.origin - tri.vertex0;
-        let u = f * s.dot(h);
-        if !(0.0..=1.0).contains(&u) {
-            return None;
-        }
-        let q = s.cross(edge1);
-        let v = f * self.direction.dot(q);
-        if v < 0.0 || u + v > 1.0 {
-            return None;
-        }
-        let t = f * edge2.dot(q);
+        self.intersect_triangle_with_settings(tri, tri_index, &RayCastSettings::default())
+    }
 
-        if t > 0.0001 {
-            return Some(Hit {
-                distance: t,
-                u,
-                v,
-                tri_index,
-            });
-        }
-        None
+    #[inline(always)]
+    fn intersect_triangle_with_settings(
+        &self,
+        tri: &Tri,
+        tri_index: usize,
+        settings: &RayCastSettings,
+    ) -> Option<Hit> {
+        TriangleTest::new(self, settings)
+            .intersect(self, tri, tri_index)
+            .map(|hit| hit.with_surface(self, tri))
     }
 
     fn intersect_bvh(&self, bvh: &Bvh) -> Option<Hit> {
+        self.intersect_bvh_with_settings(bvh, &RayCastSettings::default())
+    }
+
+    fn intersect_bvh_with_settings(&self, bvh: &Bvh, settings: &RayCastSettings) -> Option<Hit> {
+        bvh.intersect_filtered(self, settings, |_| true)
+    }
+
+    fn any_hit_bvh(&self, bvh: &Bvh) -> Option<Hit> {
+        self.any_hit_bvh_with_settings(bvh, &RayCastSettings::default())
+    }
+
+    fn any_hit_bvh_with_settings(&self, bvh: &Bvh, settings: &RayCastSettings) -> Option<Hit> {
+        bvh.any_hit_filtered(self, settings, |_| true)
+    }
+
+    fn occluded(&self, bvh: &Bvh) -> bool {
+        self.any_hit_bvh(bvh).is_some()
+    }
+
+    fn intersect_bvh_all(&self, bvh: &Bvh, max_hits: Option<usize>) -> Vec<Hit> {
+        self.intersect_bvh_all_with_settings(bvh, max_hits, &RayCastSettings::default())
+    }
+
+    fn intersect_bvh_all_with_settings(
+        &self,
+        bvh: &Bvh,
+        max_hits: Option<usize>,
+        settings: &RayCastSettings,
+    ) -> Vec<Hit> {
+        bvh.intersect_all_filtered(self, max_hits, settings, |_| true)
+    }
+}
+
+impl Bvh {
+    /// Same as [`RayCastExt::intersect_bvh_with_settings`], `filter` is called with each
+    /// candidate hit, returning false ignores it and the search goes on. For alpha tested
+    /// triangles, or ones that should be ignored.
+    ///
+    /// With spatial splits a rejected triangle can come up again from another leaf, so
+    /// `filter` should give the same answer for the same triangle
+    pub fn intersect_filtered(
+        &self,
+        ray: &RayCast3d,
+        settings: &RayCastSettings,
+        mut filter: impl FnMut(&Hit) -> bool,
+    ) -> Option<Hit> {
         #[cfg(feature = "trace")]
         let _span = info_span!("intersect_bvh").entered();
-        let mut node = &bvh.nodes[0];
+        if self.tris.is_empty() {
+            return None;
+        }
+        if let Some(wide) = &self.wide {
+            return intersect_wide_bvh(ray, self, wide, settings, &mut filter);
+        }
+        let mut node = &self.nodes[0];
         let mut stack = Vec::with_capacity(64);
         let mut best_hit: Option<Hit> = None;
+        let test = TriangleTest::new(ray, settings);
 
         // PERF: clone the ray so we can update max distance as we find hits to tighten our search,
         // more complex the scene the big the performance win
-        let mut ray = self.clone();
+        let mut ray = ray.clone();
 
         loop {
             if node.is_leaf() {
                 for i in 0..node.tri_count {
-                    let tri_index = bvh.triangle_indexs[(node.left_first + i) as usize];
-                    if let Some(hit) = ray.intersect_triangle(&bvh.tris[tri_index], tri_index) {
-                        if let Some(best) = best_hit {
-                            if hit.distance < best.distance {
-                                best_hit = Some(hit);
-                                ray.max = hit.distance; // tighten the ray
-                            }
-                        } else {
+                    let tri_index = self.triangle_indexs[(node.left_first + i) as usize];
+                    let tri = &self.tris[tri_index];
+                    if let Some(hit) = test.intersect(&ray, tri, tri_index)
+                        && best_hit.is_none_or(|best| hit.distance < best.distance)
+                    {
+                        let hit = hit.finish(&ray, self);
+                        if filter(&hit) {
                             best_hit = Some(hit);
                             ray.max = hit.distance; // tighten the ray
                         }
@@ -131,11 +505,11 @@ impl RayCastExt for RayCast3d {
                 node = stack.pop().unwrap();
                 continue;
             }
-            let mut child1 = &bvh.nodes[node.left_first as usize];
-            let mut child2 = &bvh.nodes[(node.left_first + 1) as usize];
+            let mut child1 = &self.nodes[node.left_first as usize];
+            let mut child2 = &self.nodes[(node.left_first + 1) as usize];
 
-            let mut dist1 = ray.aabb_intersection_at(&child1.aabb);
-            let mut dist2 = ray.aabb_intersection_at(&child2.aabb);
+            let mut dist1 = ray.aabb_intersection_at(&child1.aabb());
+            let mut dist2 = ray.aabb_intersection_at(&child2.aabb());
 
             // Sort the children by distance
             if dist1.unwrap_or(f32::MAX) > dist2.unwrap_or(f32::MAX) {
@@ -157,4 +531,120 @@ impl RayCastExt for RayCast3d {
         }
         best_hit
     }
+
+    /// Same as [`RayCastExt::any_hit_bvh_with_settings`], only hits `filter` accepts count,
+    /// like [`Bvh::intersect_filtered`] `filter` can see a triangle more than once
+    pub fn any_hit_filtered(
+        &self,
+        ray: &RayCast3d,
+        settings: &RayCastSettings,
+        mut filter: impl FnMut(&Hit) -> bool,
+    ) -> Option<Hit> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("any_hit_bvh").entered();
+        if self.tris.is_empty() {
+            return None;
+        }
+        if let Some(wide) = &self.wide {
+            return any_hit_wide_bvh(ray, self, wide, settings, &mut filter);
+        }
+        let test = TriangleTest::new(ray, settings);
+        let mut stack = Vec::with_capacity(64);
+        stack.push(&self.nodes[0]);
+
+        while let Some(node) = stack.pop() {
+            if node.is_leaf() {
+                for i in 0..node.tri_count {
+                    let tri_index = self.triangle_indexs[(node.left_first + i) as usize];
+                    if let Some(hit) = test.intersect(ray, &self.tris[tri_index], tri_index)
+                        && hit.distance <= ray.max
+                    {
+                        let hit = hit.finish(ray, self);
+                        if filter(&hit) {
+                            return Some(hit);
+                        }
+                    }
+                }
+                continue;
+            }
+            // any hit will do, so children aren't sorted
+            for child in [node.left_first, node.left_first + 1] {
+                let child = &self.nodes[child as usize];
+                if ray.aabb_intersection_at(&child.aabb()).is_some() {
+                    stack.push(child);
+                }
+            }
+        }
+        None
+    }
+
+    /// Same as [`RayCastExt::intersect_bvh_all_with_settings`], only hits `filter` accepts
+    /// are kept, each triangle is only tested and filtered once
+    pub fn intersect_all_filtered(
+        &self,
+        ray: &RayCast3d,
+        max_hits: Option<usize>,
+        settings: &RayCastSettings,
+        mut filter: impl FnMut(&Hit) -> bool,
+    ) -> Vec<Hit> {
+        #[cfg(feature = "trace")]
+        let _span = info_span!("intersect_bvh_all").entered();
+        let mut hits = Vec::new();
+        if self.tris.is_empty() || max_hits == Some(0) {
+            return hits;
+        }
+        let test = TriangleTest::new(ray, settings);
+        let mut stack = Vec::with_capacity(64);
+        stack.push(&self.nodes[0]);
+
+        // only tightened once max_hits are found
+        let mut ray = ray.clone();
+        // spatial splits can reference a triangle from more than one leaf
+        let mut tested = HashSet::new();
+
+        while let Some(node) = stack.pop() {
+            if node.is_leaf() {
+                for i in 0..node.tri_count {
+                    let tri_index = self.triangle_indexs[(node.left_first + i) as usize];
+                    if self.settings.spatial_splits && !tested.insert(tri_index) {
+                        continue;
+                    }
+                    let Some(hit) = test.intersect(&ray, &self.tris[tri_index], tri_index) else {
+                        continue;
+                    };
+                    if hit.distance > ray.max {
+                        continue;
+                    }
+                    let hit = hit.finish(&ray, self);
+                    if !filter(&hit) {
+                        continue;
+                    }
+                    hits.push(hit);
+                    if let Some(max_hits) = max_hits
+                        && hits.len() > max_hits
+                    {
+                        sort_hits(&mut hits);
+                        hits.truncate(max_hits);
+                        ray.max = hits[max_hits - 1].distance; // tighten the ray
+                    }
+                }
+                continue;
+            }
+            // every hit is kept, so children aren't sorted
+            for child in [node.left_first, node.left_first + 1] {
+                let child = &self.nodes[child as usize];
+                if ray.aabb_intersection_at(&child.aabb()).is_some() {
+                    stack.push(child);
+                }
+            }
+        }
+        sort_hits(&mut hits);
+        hits
+    }
+}
+
+/// Closest first
+#[inline]
+fn sort_hits(hits: &mut [Hit]) {
+    hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
 }
@@ -0,0 +1,321 @@
+use bevy::{
+    math::bounding::{Aabb3d, RayCast3d},
+    prelude::*,
+};
+
+use crate::{
+    aabb::Aabb3dExt,
+    bvh::Bvh,
+    util::{Hit, RayCastSettings, TriangleTest},
+};
+
+/// Number of children per [`WideBvhNode`]
+pub const WIDE_BVH_WIDTH: usize = 4;
+
+/// A node of a [`WideBvh`], holding the boxes of up to 4 children in SoA layout so a ray is
+/// tested against all of them at once, glam's `Vec4` uses SSE2, NEON or simd128 when available
+/// and falls back to scalar code otherwise.
+///
+/// Unused slots have an empty box that never hits.
+#[repr(C, align(64))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WideBvhNode {
+    pub min_x: Vec4,
+    pub min_y: Vec4,
+    pub min_z: Vec4,
+    pub max_x: Vec4,
+    pub max_y: Vec4,
+    pub max_z: Vec4,
+    /// Index of the child node, or of the first entry in [`Bvh::triangle_indexs`] for leaves
+    pub child: [u32; WIDE_BVH_WIDTH],
+    /// Number of triangles for leaves, 0 for nodes
+    pub tri_count: [u32; WIDE_BVH_WIDTH],
+}
+
+impl Default for WideBvhNode {
+    fn default() -> Self {
+        WideBvhNode {
+            min_x: Vec4::splat(1e30),
+            min_y: Vec4::splat(1e30),
+            min_z: Vec4::splat(1e30),
+            max_x: Vec4::splat(-1e30),
+            max_y: Vec4::splat(-1e30),
+            max_z: Vec4::splat(-1e30),
+            child: [0; WIDE_BVH_WIDTH],
+            tri_count: [0; WIDE_BVH_WIDTH],
+        }
+    }
+}
+
+impl WideBvhNode {
+    fn set_child(&mut self, slot: usize, aabb: &Aabb3d, child: u32, tri_count: u32) {
+        self.min_x[slot] = aabb.min.x;
+        self.min_y[slot] = aabb.min.y;
+        self.min_z[slot] = aabb.min.z;
+        self.max_x[slot] = aabb.max.x;
+        self.max_y[slot] = aabb.max.y;
+        self.max_z[slot] = aabb.max.z;
+        self.child[slot] = child;
+        self.tri_count[slot] = tri_count;
+    }
+
+    /// Box of the child in `slot`, `None` for unused slots
+    #[inline]
+    pub(crate) fn child_aabb(&self, slot: usize) -> Option<Aabb3d> {
+        let min = vec3a(self.min_x[slot], self.min_y[slot], self.min_z[slot]);
+        let max = vec3a(self.max_x[slot], self.max_y[slot], self.max_z[slot]);
+        min.cmple(max).all().then_some(Aabb3d { min, max })
+    }
+
+    /// Entry distance of the ray into each child box, infinity for misses, same rules as
+    /// [`RayCast3d::aabb_intersection_at`]
+    #[inline]
+    fn intersect(&self, ray: &WideRay) -> Vec4 {
+        let (near_x, far_x) = if ray.positive.x {
+            (self.min_x, self.max_x)
+        } else {
+            (self.max_x, self.min_x)
+        };
+        let (near_y, far_y) = if ray.positive.y {
+            (self.min_y, self.max_y)
+        } else {
+            (self.max_y, self.min_y)
+        };
+        let (near_z, far_z) = if ray.positive.z {
+            (self.min_z, self.max_z)
+        } else {
+            (self.max_z, self.min_z)
+        };
+        let t_near = ((near_x - ray.origin_x) * ray.recip_x)
+            .max((near_y - ray.origin_y) * ray.recip_y)
+            .max((near_z - ray.origin_z) * ray.recip_z)
+            .max(Vec4::ZERO);
+        let t_far = ((far_x - ray.origin_x) * ray.recip_x)
+            .min((far_y - ray.origin_y) * ray.recip_y)
+            .min((far_z - ray.origin_z) * ray.recip_z)
+            .min(ray.max);
+        Vec4::select(t_near.cmple(t_far), t_near, Vec4::INFINITY)
+    }
+}
+
+/// A 4 wide bvh collapsed from the binary tree of a [`Bvh`], sharing its triangles.
+///
+/// Built with [`BvhBuildSettings::wide`](crate::bvh::BvhBuildSettings::wide), ray casts
+/// against the [`Bvh`] then use it automatically.
+#[derive(Debug, Default, Clone)]
+pub struct WideBvh {
+    pub nodes: Vec<WideBvhNode>,
+}
+
+impl WideBvh {
+    /// Collapses the binary tree, each wide node pulls up grandchildren in place of its
+    /// largest children until it has 4
+    pub fn collapse(bvh: &Bvh) -> WideBvh {
+        let mut wide = WideBvh {
+            nodes: Vec::with_capacity(bvh.nodes.len() / 2 + 1),
+        };
+        if bvh.tris.is_empty() {
+            return wide;
+        }
+        let root = &bvh.nodes[0];
+        if root.is_leaf() {
+            // a single leaf still needs a node to hold its box
+            let mut node = WideBvhNode::default();
+            node.set_child(0, &root.aabb(), root.left_first, root.tri_count);
+            wide.nodes.push(node);
+        } else {
+            wide.collapse_node(bvh, 0);
+        }
+        wide
+    }
+
+    /// Pushes the wide node for the interior binary node `node_idx`, returning its index
+    fn collapse_node(&mut self, bvh: &Bvh, node_idx: usize) -> u32 {
+        let first = bvh.nodes[node_idx].left_first as usize;
+        let mut children = vec![first, first + 1];
+        while children.len() < WIDE_BVH_WIDTH {
+            let Some((i, _)) = children
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| !bvh.nodes[**c].is_leaf())
+                .max_by(|(_, a), (_, b)| {
+                    let a = bvh.nodes[**a].aabb().area();
+                    let b = bvh.nodes[**b].aabb().area();
+                    a.total_cmp(&b)
+                })
+            else {
+                break;
+            };
+            let grandchild = bvh.nodes[children[i]].left_first as usize;
+            children[i] = grandchild;
+            children.insert(i + 1, grandchild + 1);
+        }
+
+        let wide_idx = self.nodes.len();
+        self.nodes.push(WideBvhNode::default());
+        for (slot, child_idx) in children.into_iter().enumerate() {
+            let child = &bvh.nodes[child_idx];
+            let index = if child.is_leaf() {
+                child.left_first
+            } else {
+                self.collapse_node(bvh, child_idx)
+            };
+            self.nodes[wide_idx].set_child(slot, &child.aabb(), index, child.tri_count);
+        }
+        wide_idx as u32
+    }
+}
+
+/// Ray splatted across lanes, set up once per traversal
+struct WideRay {
+    origin_x: Vec4,
+    origin_y: Vec4,
+    origin_z: Vec4,
+    recip_x: Vec4,
+    recip_y: Vec4,
+    recip_z: Vec4,
+    max: Vec4,
+    positive: BVec3,
+}
+
+impl WideRay {
+    fn new(ray: &RayCast3d) -> Self {
+        let recip = ray.direction_recip();
+        WideRay {
+            origin_x: Vec4::splat(ray.origin.x),
+            origin_y: Vec4::splat(ray.origin.y),
+            origin_z: Vec4::splat(ray.origin.z),
+            recip_x: Vec4::splat(recip.x),
+            recip_y: Vec4::splat(recip.y),
+            recip_z: Vec4::splat(recip.z),
+            max: Vec4::splat(ray.max),
+            positive: BVec3::new(
+                ray.direction.x.is_sign_positive(),
+                ray.direction.y.is_sign_positive(),
+                ray.direction.z.is_sign_positive(),
+            ),
+        }
+    }
+}
+
+/// Closest hit against the wide tree of `bvh`, visiting children nearest first
+pub(crate) fn intersect_wide_bvh(
+    ray: &RayCast3d,
+    bvh: &Bvh,
+    wide: &WideBvh,
+    settings: &RayCastSettings,
+    filter: &mut impl FnMut(&Hit) -> bool,
+) -> Option<Hit> {
+    #[cfg(feature = "trace")]
+    let _span = info_span!("intersect_wide_bvh").entered();
+    if wide.nodes.is_empty() {
+        return None;
+    }
+    let mut ray = ray.clone();
+    let mut wide_ray = WideRay::new(&ray);
+    let mut best_hit: Option<Hit> = None;
+    let test = TriangleTest::new(&ray, settings);
+    let mut stack = Vec::with_capacity(64);
+    stack.push((0u32, 0.0f32));
+
+    while let Some((node_idx, entry)) = stack.pop() {
+        // a closer hit may have been found since this node was pushed
+        if entry > ray.max {
+            continue;
+        }
+        let node = &wide.nodes[node_idx as usize];
+        let dists = node.intersect(&wide_ray);
+
+        // order the hit children nearest first
+        let mut order = [(0.0f32, 0usize); WIDE_BVH_WIDTH];
+        let mut hits = 0;
+        for slot in 0..WIDE_BVH_WIDTH {
+            let dist = dists[slot];
+            if dist == f32::INFINITY {
+                continue;
+            }
+            let mut i = hits;
+            while i > 0 && order[i - 1].0 > dist {
+                order[i] = order[i - 1];
+                i -= 1;
+            }
+            order[i] = (dist, slot);
+            hits += 1;
+        }
+
+        // leaves are tested right away, nodes pushed farthest first so the nearest pops next
+        for &(dist, slot) in order[..hits].iter().rev() {
+            if node.tri_count[slot] == 0 {
+                stack.push((node.child[slot], dist));
+            }
+        }
+        for &(dist, slot) in &order[..hits] {
+            let tri_count = node.tri_count[slot];
+            if tri_count == 0 || dist > ray.max {
+                continue;
+            }
+            let first = node.child[slot];
+            for i in first..first + tri_count {
+                let tri_index = bvh.triangle_indexs[i as usize];
+                if let Some(hit) = test.intersect(&ray, &bvh.tris[tri_index], tri_index)
+                    && best_hit.is_none_or(|best| hit.distance < best.distance)
+                {
+                    let hit = hit.finish(&ray, bvh);
+                    if filter(&hit) {
+                        best_hit = Some(hit);
+                        ray.max = hit.distance; // tighten the ray
+                        wide_ray.max = Vec4::splat(ray.max);
+                    }
+                }
+            }
+        }
+    }
+    best_hit
+}
+
+/// First hit found against the wide tree of `bvh` within `ray.max`, children in slot order
+pub(crate) fn any_hit_wide_bvh(
+    ray: &RayCast3d,
+    bvh: &Bvh,
+    wide: &WideBvh,
+    settings: &RayCastSettings,
+    filter: &mut impl FnMut(&Hit) -> bool,
+) -> Option<Hit> {
+    #[cfg(feature = "trace")]
+    let _span = info_span!("any_hit_wide_bvh").entered();
+    if wide.nodes.is_empty() {
+        return None;
+    }
+    let wide_ray = WideRay::new(ray);
+    let test = TriangleTest::new(ray, settings);
+    let mut stack = Vec::with_capacity(64);
+    stack.push(0u32);
+
+    while let Some(node_idx) = stack.pop() {
+        let node = &wide.nodes[node_idx as usize];
+        let dists = node.intersect(&wide_ray);
+        for slot in 0..WIDE_BVH_WIDTH {
+            if dists[slot] == f32::INFINITY {
+                continue;
+            }
+            let tri_count = node.tri_count[slot];
+            if tri_count == 0 {
+                stack.push(node.child[slot]);
+                continue;
+            }
+            let first = node.child[slot];
+            for i in first..first + tri_count {
+                let tri_index = bvh.triangle_indexs[i as usize];
+                if let Some(hit) = test.intersect(ray, &bvh.tris[tri_index], tri_index)
+                    && hit.distance <= ray.max
+                {
+                    let hit = hit.finish(ray, bvh);
+                    if filter(&hit) {
+                        return Some(hit);
+                    }
+                }
+            }
+        }
+    }
+    None
+}